@@ -0,0 +1,86 @@
+//! Allocations and time per event in the log layer
+//!
+//! Run with `cargo bench --bench log_layer --features testing`. An event in two
+//! spans took 23 allocations before the locations and span attributes were
+//! shared between the log records, and 3 after.
+
+use axum_otlp_honeycomb::testing::init_otlp_log_layer_with_exporter;
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    logs::{LogBatch, LogExporter},
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+use tracing_subscriber::prelude::*;
+
+/// Counts the allocations made by the program
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Drops the records, so only the allocations of the layer are counted
+#[derive(Debug)]
+struct NoopExporter;
+
+impl LogExporter for NoopExporter {
+    async fn export(&self, batch: LogBatch<'_>) -> OTelSdkResult {
+        black_box(batch);
+        Ok(())
+    }
+}
+
+const EVENTS: usize = 100_000;
+
+fn main() {
+    let _subscriber = tracing_subscriber::registry()
+        .with(init_otlp_log_layer_with_exporter(NoopExporter))
+        .set_default();
+
+    let request = tracing::info_span!("request", method = "GET", route = "/users/{id}");
+    let _request = request.enter();
+    let handler = tracing::info_span!("handler", user = 42);
+    let _handler = handler.enter();
+
+    let user = "alice";
+    let run = |events| {
+        for i in 0..events {
+            tracing::info!(user, attempt = i, ok = true, "user logged in");
+        }
+    };
+    run(1_000);
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    run(EVENTS);
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!(
+        "{:.1} allocations and {:.0} ns per event",
+        allocations as f64 / EVENTS as f64,
+        elapsed.as_nanos() as f64 / EVENTS as f64,
+    );
+}
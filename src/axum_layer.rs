@@ -3,22 +3,54 @@
 //! The code is a combination of code from tower-http::trace and
 //! axum-tracing-opentelemetry
 
-use axum::extract::MatchedPath;
-use http::{HeaderMap, HeaderValue, Request, Response};
-use opentelemetry::trace::{TraceContextExt, TraceFlags};
+use crate::{
+    dynamic_attributes::DynamicAttributes,
+    metrics::{ActiveRequest, HttpMetrics},
+    panic,
+    rate_limit::SpanRateLimiter,
+    response_body::ResponseBody,
+};
+use axum::extract::{ConnectInfo, MatchedPath};
+use http::{HeaderMap, HeaderName, HeaderValue, Request, Response};
+use opentelemetry::trace::{SpanKind, TraceContextExt, TraceFlags};
+use opentelemetry_sdk::trace::{IdGenerator, RandomIdGenerator};
 use pin_project_lite::pin_project;
 use std::{
-    collections::HashMap, error::Error, future::Future, pin::Pin, task::Poll, time::Instant,
+    any::Any,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    error::Error,
+    future::Future,
+    hash::{DefaultHasher, Hasher},
+    net::SocketAddr,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
+    time::{Duration, Instant, SystemTime},
 };
 use tracing::{Span, field::Empty, info_span};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
+/// Target of the events logged by `AxumOtelLayer::with_error_logs`
+pub(crate) const ERROR_LOG_TARGET: &str = "axum_otlp_honeycomb::error_log";
+
+/// Name of the request span created by `make_span`
+const REQUEST_SPAN_NAME: &str = "HTTP request";
+
+/// Whether a span is a request span created by `make_span`
+#[cfg(feature = "logs")]
+pub(crate) fn is_request_span(meta: &tracing::Metadata<'_>) -> bool {
+    meta.name() == REQUEST_SPAN_NAME && meta.target() == module_path!()
+}
+
 /// function to create the tracing layer
 #[must_use]
 #[allow(unused)]
 pub fn opentelemetry_tracing_layer() -> AxumOtelLayer {
     AxumOtelLayer {
         extract_parent: true,
+        ..Default::default()
     }
 }
 
@@ -29,9 +61,49 @@ pub fn opentelemetry_tracing_layer() -> AxumOtelLayer {
 pub fn opentelemetry_tracing_layer_without_parent() -> AxumOtelLayer {
     AxumOtelLayer {
         extract_parent: false,
+        ..Default::default()
+    }
+}
+
+/// A shared callback stored on the layer.
+///
+/// Only exists to give the layer a `Debug` implementation.
+struct Hook<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> Clone for Hook<F> {
+    fn clone(&self) -> Self {
+        Hook(Arc::clone(&self.0))
+    }
+}
+
+impl<F: ?Sized> std::fmt::Debug for Hook<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+impl<F: ?Sized> std::ops::Deref for Hook<F> {
+    type Target = F;
+    fn deref(&self) -> &F {
+        &self.0
     }
 }
 
+type SpanStartHook = dyn Fn(&Span, &http::request::Parts) + Send + Sync;
+type SpanEndHook = dyn Fn(&Span, Option<&http::response::Parts>, Duration) + Send + Sync;
+type ResourceSnapshot = dyn Fn() -> Vec<(&'static str, i64)> + Send + Sync;
+type SkipPredicate = dyn Fn(&str) -> bool + Send + Sync;
+type FeatureFlags = dyn Fn(&http::request::Parts) -> Vec<(String, String)> + Send + Sync;
+type UserIdExtractor = dyn Fn(&http::request::Parts) -> Option<String> + Send + Sync;
+type UserAgentClassifier = dyn Fn(&str) -> Option<String> + Send + Sync;
+type TlsServerNameGetter = dyn Fn(&http::Extensions) -> Option<String> + Send + Sync;
+type StreamIdGetter = dyn Fn(&http::Extensions) -> Option<u32> + Send + Sync;
+type RouteNormalizer = dyn Fn(&str) -> String + Send + Sync;
+type SpanNamer = dyn Fn(&str, &str) -> String + Send + Sync;
+type IdempotencyKeyHasher = dyn Fn(&[u8]) -> String + Send + Sync;
+type RequestResponseAttributes =
+    dyn Fn(&http::request::Parts, &http::response::Parts) -> Vec<(String, String)> + Send + Sync;
+
 /// layer/middleware for axum:
 ///
 /// - propagate `OpenTelemetry` context (`trace_id`,...) to server
@@ -41,6 +113,968 @@ pub fn opentelemetry_tracing_layer_without_parent() -> AxumOtelLayer {
 #[derive(Default, Debug, Clone)]
 pub struct AxumOtelLayer {
     extract_parent: bool,
+    on_span_start: Option<Hook<SpanStartHook>>,
+    on_span_end: Option<Hook<SpanEndHook>>,
+    pub(crate) recorded_trailers: Vec<HeaderName>,
+    span_rate_limit: Option<Arc<SpanRateLimiter>>,
+    dynamic_attributes: Option<DynamicAttributes>,
+    span_kind: Option<SpanKind>,
+    route_span_kinds: HashMap<String, SpanKind>,
+    accept_language: Option<AcceptLanguage>,
+    streaming_span_names: bool,
+    honeycomb_dataset: Option<String>,
+    deployment: Option<(String, bool)>,
+    route_segments: usize,
+    path_params_prefix: Option<String>,
+    redacted_path_params: HashSet<String>,
+    route_normalizer: Option<Hook<RouteNormalizer>>,
+    omit_otel_name: bool,
+    span_namer: Option<Hook<SpanNamer>>,
+    claim_attributes: Vec<(String, String)>,
+    operation_id_attribute: Option<String>,
+    resource_snapshot: Option<Hook<ResourceSnapshot>>,
+    redacted_headers: Arc<HashSet<HeaderName>>,
+    omit_traceparent: bool,
+    lifecycle_events: bool,
+    request_start_header: Option<HeaderName>,
+    rate_limit_headers: Option<(HeaderName, HeaderName)>,
+    http_metrics: Option<Arc<HttpMetrics>>,
+    pub(crate) error_logs: bool,
+    request_response_attributes: Option<Hook<RequestResponseAttributes>>,
+    feature_flags: Option<Hook<FeatureFlags>>,
+    user_id_extractor: Option<Hook<UserIdExtractor>>,
+    user_agent_classifier: Option<Hook<UserAgentClassifier>>,
+    tls_server_name: Option<Hook<TlsServerNameGetter>>,
+    http2_stream_id: Option<Hook<StreamIdGetter>>,
+    panic_locations: bool,
+    start_timestamp: bool,
+    conditional_requests: bool,
+    fallback_detection: bool,
+    ok_status: bool,
+    client_error_status: ClientErrorStatus,
+    request_id: Option<(HeaderName, RequestIdMode)>,
+    peer_service_header: Option<HeaderName>,
+    skip_paths: HashSet<String>,
+    skip_predicate: Option<Hook<SkipPredicate>>,
+    forwarded_for: Option<ForwardedFor>,
+    idempotency_key_header: Option<HeaderName>,
+    request_headers: Vec<(HeaderName, String)>,
+    headers_blob: bool,
+    max_attribute_length: Option<usize>,
+    truncation_markers: bool,
+    idempotency_key_hasher: Option<Hook<IdempotencyKeyHasher>>,
+}
+
+/// Number of times the request was resent, recorded as
+/// `http.request.resend_count`.
+///
+/// A retry layer placed below this layer (closer to the handler) inserts it in
+/// the response extensions. It is also read from the request extensions, for
+/// layers above this one that know about earlier attempts. The value in the
+/// response wins. Following the `OpenTelemetry` conventions the first attempt
+/// is not a resend, so it should be `attempts - 1` and is only recorded when
+/// it is larger than 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResendCount(pub u32);
+
+/// Claims from a JWT that the application has already validated.
+///
+/// This crate never decodes or validates tokens. The authentication code
+/// inserts the (validated) claims as an extension, and the claims given to
+/// [`AxumOtelLayer::with_claim_attributes`] are recorded on the request span.
+///
+/// Insert it in the request extensions from a layer above this one, or in the
+/// response extensions from a layer or handler below it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JwtClaims(pub HashMap<String, String>);
+
+/// The `operationId` of the route, eg. from an `OpenAPI` spec, recorded as
+/// `operation.id` (see [`AxumOtelLayer::with_operation_id_attribute`]).
+///
+/// The routing code inserts it in the request extensions from a layer above
+/// this one, or in the response extensions from a layer or handler below it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperationId(pub String);
+
+/// The span name of the request, recorded as `otel.name` instead of
+/// `<method> <route>`.
+///
+/// It takes precedence over [`AxumOtelLayer::with_span_namer`], and is also
+/// used with [`AxumOtelLayer::without_otel_name`]. Insert it in the request
+/// extensions from a layer above this one, or in the response extensions from
+/// a layer or handler below it, eg. by returning `Extension(SpanName(..))` with
+/// the response. The name in the response wins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanName(pub String);
+
+/// Marks a response of the router's fallback handler, recorded as
+/// `http.route.fallback = true`.
+///
+/// The fallback handler returns it with the response, eg.
+/// `(Extension(Fallback), StatusCode::NOT_FOUND)`. To detect the fallback
+/// without it, see [`AxumOtelLayer::with_fallback_detection`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fallback;
+
+/// The SNI hostname the client asked for in the TLS handshake, recorded as
+/// `tls.client.server_name`.
+///
+/// axum doesn't know the SNI, so the TLS acceptor (or a layer above this one
+/// that gets it from the connection) inserts it in the request extensions. For
+/// acceptors with their own extension type use
+/// [`AxumOtelLayer::with_tls_server_name`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsServerName(pub String);
+
+/// The id of the HTTP/2 stream the request came in on, recorded as
+/// `http2.stream_id`.
+///
+/// hyper doesn't expose the stream id to axum, so a server that knows it
+/// inserts it in the request extensions. With the stream id and the connection,
+/// the spans of requests multiplexed on one connection can be lined up to look
+/// for head-of-line blocking. For servers with their own extension type use
+/// [`AxumOtelLayer::with_http2_stream_id`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Http2StreamId(pub u32);
+
+/// The rate limit state of the request, recorded as `http.ratelimit.limit`
+/// and `http.ratelimit.remaining`.
+///
+/// A rate limiting layer or handler below this one inserts it in the response
+/// extensions. It takes precedence over the rate limit headers of the response
+/// (see [`AxumOtelLayer::with_rate_limit_headers`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The number of requests allowed in the window
+    pub limit: u64,
+    /// The number of requests left in the window
+    pub remaining: u64,
+}
+
+/// How the `Accept-Language` header is recorded, see
+/// [`AxumOtelLayer::with_accept_language`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptLanguage {
+    /// Only the primary language subtag of the preferred language, eg. `da`
+    Primary,
+    /// The header value as sent by the client
+    Full,
+}
+
+/// Where the request id comes from, see [`AxumOtelLayer::with_request_id`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestIdMode {
+    /// Use the id sent by the client or proxy, and generate one when it is missing
+    AcceptIncoming,
+    /// Always generate a new id, replacing the one that was sent
+    AlwaysGenerate,
+}
+
+/// The `otel.status_code` of requests with a 4xx response, see
+/// [`AxumOtelLayer::with_client_error_status`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientErrorStatus {
+    /// Leave the status unset, as the semantic conventions recommend for server spans
+    #[default]
+    Unset,
+    /// `OK`, the server handled the request as it should
+    Ok,
+    /// `ERROR`, as for 5xx responses
+    Error,
+}
+
+/// Which entry of `X-Forwarded-For` / `Forwarded` is the client, see
+/// [`AxumOtelLayer::with_trusted_forwarded_headers`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardedFor {
+    /// The first entry, as added by the first proxy. Use it when all proxies
+    /// in front of the service are trusted.
+    Leftmost,
+    /// The last entry, as added by the proxy in front of the service. It can't
+    /// be spoofed by the client. When both `Forwarded` and `X-Forwarded-For`
+    /// are sent, and their last entries differ, neither is used.
+    Rightmost,
+}
+
+impl AxumOtelLayer {
+    /// Run `hook` when the request span has been created.
+    ///
+    /// The hook is called from `call` with the new span and the request
+    /// head, before the request is passed on to the inner service.
+    ///
+    /// The hook runs on the request path, so it must be cheap and must not block.
+    #[must_use]
+    pub fn on_span_start<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Span, &http::request::Parts) + Send + Sync + 'static,
+    {
+        self.on_span_start = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    /// Run `hook` when the response (or error) for the request span is ready.
+    ///
+    /// The hook is called from `poll` with the span, the response head (`None`
+    /// if the inner service failed) and the time since the request was received.
+    ///
+    /// The hook runs on the response path, so it must be cheap and must not block.
+    #[must_use]
+    pub fn on_span_end<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Span, Option<&http::response::Parts>, Duration) + Send + Sync + 'static,
+    {
+        self.on_span_end = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    /// Record the given response trailers as span attributes.
+    ///
+    /// Each trailer is recorded as `http.response.trailer.<name>` when the
+    /// response body ends. Names that are not valid header names are ignored.
+    ///
+    /// `grpc-status` is also recorded as `rpc.grpc.status_code`, and marks the
+    /// span as an error for the codes the gRPC conventions treat as server errors.
+    /// The names are also looked up in the response headers, as gRPC sends
+    /// "trailers-only" responses when there is no body.
+    ///
+    /// ```
+    /// # use axum_otlp_honeycomb::opentelemetry_tracing_layer;
+    /// let layer = opentelemetry_tracing_layer().with_recorded_trailers(["grpc-status", "grpc-message"]);
+    /// ```
+    #[must_use]
+    pub fn with_recorded_trailers<I, N>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: AsRef<str>,
+    {
+        self.recorded_trailers = names
+            .into_iter()
+            .filter_map(|name| HeaderName::try_from(name.as_ref().to_ascii_lowercase()).ok())
+            .collect();
+        self
+    }
+}
+
+impl AxumOtelLayer {
+    /// Create at most `spans_per_second` request spans per second.
+    ///
+    /// Requests above the limit are still handled, but without a span.
+    /// This is a circuit-breaker against runaway endpoints flooding Honeycomb,
+    /// not a replacement for sampling.
+    ///
+    /// The dropped spans are counted in the `http.server.spans.dropped` metric
+    /// through the global meter provider.
+    #[must_use]
+    pub fn with_span_rate_limit(mut self, spans_per_second: u32) -> Self {
+        self.span_rate_limit = Some(Arc::new(SpanRateLimiter::new(spans_per_second)));
+        self
+    }
+
+    /// Set the `SpanKind` of the request spans, the default is `Server`.
+    #[must_use]
+    pub fn with_span_kind(mut self, kind: SpanKind) -> Self {
+        self.span_kind = Some(kind);
+        self
+    }
+
+    /// Set the `SpanKind` for the requests to one route, eg. `Consumer` for
+    /// a webhook endpoint.
+    ///
+    /// `route` is the route as given to the router, like `/webhooks/{source}`.
+    /// Other routes use the kind set by `with_span_kind`.
+    #[must_use]
+    pub fn with_route_span_kind(mut self, route: impl Into<String>, kind: SpanKind) -> Self {
+        self.route_span_kinds.insert(route.into(), kind);
+        self
+    }
+
+    fn span_kind(&self, route: &str) -> &SpanKind {
+        self.route_span_kinds
+            .get(route)
+            .or(self.span_kind.as_ref())
+            .unwrap_or(&SpanKind::Server)
+    }
+
+    /// Record the `Accept-Language` header as `http.request.header.accept_language`.
+    ///
+    /// [`AcceptLanguage::Primary`] keeps the cardinality low by recording only the
+    /// primary subtag of the preferred language, so `da-DK,da;q=0.9,en;q=0.8`
+    /// is recorded as `da`.
+    #[must_use]
+    pub fn with_accept_language(mut self, mode: AcceptLanguage) -> Self {
+        self.accept_language = Some(mode);
+        self
+    }
+
+    /// Name the spans of WebSocket and Server-Sent Events requests
+    /// `WS <route>` and `SSE <route>` instead of `<method> <route>`.
+    ///
+    /// WebSocket requests are detected by the `Upgrade: websocket` header,
+    /// SSE requests by `text/event-stream` in the `Accept` header.
+    /// This makes the long-lived streaming spans stand out in the trace list.
+    #[must_use]
+    pub fn with_streaming_span_names(mut self, enable: bool) -> Self {
+        self.streaming_span_names = enable;
+        self
+    }
+
+    /// Record `honeycomb.dataset` on every request span.
+    ///
+    /// Use the dataset the spans are sent to, eg. the value of the
+    /// `x-honeycomb-dataset` header for Classic keys. Helps verifying that
+    /// traces end up where they are expected when several datasets are used.
+    #[must_use]
+    pub fn with_honeycomb_dataset(mut self, dataset: impl Into<String>) -> Self {
+        self.honeycomb_dataset = Some(dataset.into());
+        self
+    }
+
+    /// Record `deployment.color` and `deployment.canary` on every request span.
+    ///
+    /// The resource attributes of `OtlpConfigBuilder::deployment_color()` and
+    /// `canary()` are usually enough, this is for pipelines that drop the
+    /// resource attributes or for values only known to the router.
+    #[must_use]
+    pub fn with_deployment(mut self, color: impl Into<String>, canary: bool) -> Self {
+        self.deployment = Some((color.into(), canary));
+        self
+    }
+
+    /// Record the first `count` segments of the matched route as
+    /// `http.route.segment.0`, `http.route.segment.1`, ...
+    ///
+    /// For `/api/v2/users/{id}` and a count of 2, `api` and `v2` are recorded,
+    /// so traffic can be rolled up by API section. The segments come from the
+    /// route template, so the cardinality is bounded by the number of routes.
+    #[must_use]
+    pub fn with_route_segments(mut self, count: usize) -> Self {
+        self.route_segments = count;
+        self
+    }
+
+    /// Record the parameters of the matched route as `<prefix>.<name>`, eg.
+    /// `http.route.param.id = 42` for `/users/{id}` with the prefix
+    /// `http.route.param`.
+    ///
+    /// The values are taken from the path as sent, without percent-decoding,
+    /// and a wildcard `{*rest}` gets the rest of the path. Only parameters
+    /// spanning a whole segment are recorded, and nothing is recorded for
+    /// routes without parameters or when there is no matched route.
+    ///
+    /// Values longer than [`with_max_attribute_length()`](Self::with_max_attribute_length)
+    /// are cut, and parameters whose name contains `token` or is given to
+    /// [`with_redacted_path_params()`](Self::with_redacted_path_params) are
+    /// recorded as `[REDACTED]`.
+    #[must_use]
+    pub fn with_path_params(mut self, prefix: impl Into<String>) -> Self {
+        self.path_params_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Record the path parameters in `names` as `[REDACTED]`, for
+    /// [`with_path_params()`](Self::with_path_params). The values are still
+    /// part of `url.path`.
+    ///
+    /// ```
+    /// # use axum_otlp_honeycomb::opentelemetry_tracing_layer;
+    /// let layer = opentelemetry_tracing_layer()
+    ///     .with_path_params("http.route.param")
+    ///     .with_redacted_path_params(["email"]);
+    /// ```
+    #[must_use]
+    pub fn with_redacted_path_params<I, N>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<String>,
+    {
+        self.redacted_path_params
+            .extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Rewrite the matched route before it is recorded as `http.route`, used
+    /// in the span name and the metrics, eg. to group versioned routes:
+    /// `/v1/users/{id}` and `/v2/users/{id}` as `/{version}/users/{id}`.
+    ///
+    /// When the rewritten route differs, the matched route is kept as
+    /// `http.route.raw` to help debugging the rules. `with_skip_paths` and
+    /// `with_route_span_kind` still match the matched route.
+    #[must_use]
+    pub fn with_route_normalizer<F>(mut self, normalize: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.route_normalizer = Some(Hook(Arc::new(normalize)));
+        self
+    }
+
+    /// The normalized `route`, if there is a normalizer and it changed the route
+    fn normalize_route(&self, route: &str) -> Option<String> {
+        let normalize = self.route_normalizer.as_ref()?;
+        Some(normalize(route)).filter(|normalized| normalized != route)
+    }
+
+    /// Don't set `otel.name` on the request spans.
+    ///
+    /// The span is then exported with the tracing span name `HTTP request`,
+    /// for teams that derive the name in Honeycomb from the other fields.
+    /// The default is to set `otel.name` to `<method> <route>`.
+    #[must_use]
+    pub fn without_otel_name(mut self) -> Self {
+        self.omit_otel_name = true;
+        self
+    }
+
+    /// Set `otel.name` of the request spans to `name(method, route)`, eg.
+    /// `|_, route| route.to_string()` for just the route. The default is
+    /// `<method> <route>`.
+    ///
+    /// The tracing span is always named `HTTP request`, only `otel.name` is
+    /// changed. The names of `with_streaming_span_names` are kept.
+    #[must_use]
+    pub fn with_span_namer<F>(mut self, name: F) -> Self
+    where
+        F: Fn(&str, &str) -> String + Send + Sync + 'static,
+    {
+        self.span_namer = Some(Hook(Arc::new(name)));
+        self
+    }
+
+    /// The `otel.name` of a request with `method` to `route`
+    fn otel_name(&self, method: &str, route: &str) -> String {
+        match &self.span_namer {
+            Some(name) => name(method, route),
+            None => format!("{method} {route}"),
+        }
+    }
+
+    /// Don't add the `traceparent` and `tracestate` headers to the responses.
+    ///
+    /// By default the span context of the request is returned in a W3C
+    /// `traceparent` header, so clients and browser RUM can stitch their traces
+    /// to the server trace. Its sampled flag is the decision of this service, so
+    /// unsampled requests get `-00` and callers can follow the decision made at
+    /// the edge. The `tracestate` of the caller is returned with it, when it is
+    /// not empty. Neither is added when the handler set a `traceparent`.
+    #[must_use]
+    pub fn without_traceparent_response(mut self) -> Self {
+        self.omit_traceparent = true;
+        self
+    }
+
+    /// Record the start of the request as `request.start_unix_ms`, in
+    /// milliseconds since the Unix epoch.
+    ///
+    /// The span already has its start time, this is for derived columns in
+    /// Honeycomb that need it as a field.
+    #[must_use]
+    pub fn with_start_timestamp(mut self) -> Self {
+        self.start_timestamp = true;
+        self
+    }
+
+    /// Record `http.route.fallback = true` for requests without a matched
+    /// route that don't get a 404 or 405 response, as they were served by the
+    /// router's fallback handler.
+    ///
+    /// The layer must be added with `Router::layer`, as the matched route isn't
+    /// known outside the router and all requests would count as fallback.
+    /// Fallback handlers that return 404 are only recorded with the
+    /// [`Fallback`] extension.
+    #[must_use]
+    pub fn with_fallback_detection(mut self) -> Self {
+        self.fallback_detection = true;
+        self
+    }
+
+    /// Record `otel.status_code = OK` for 1xx, 2xx and 3xx responses.
+    ///
+    /// By default the status is left unset, following the `OpenTelemetry`
+    /// conventions for HTTP server spans. It is recorded when the response body
+    /// is done, so a failing body or a `grpc-status` trailer still sets `ERROR`.
+    #[must_use]
+    pub fn with_ok_status(mut self) -> Self {
+        self.ok_status = true;
+        self
+    }
+
+    /// Set the `otel.status_code` of 4xx responses, the default is
+    /// [`ClientErrorStatus::Unset`].
+    ///
+    /// With [`ClientErrorStatus::Error`] they are handled like 5xx responses,
+    /// with `error.source = http_status` and the error log of `with_error_logs`.
+    #[must_use]
+    pub fn with_client_error_status(mut self, status: ClientErrorStatus) -> Self {
+        self.client_error_status = status;
+        self
+    }
+
+    /// Record `http.request.conditional = true` on requests with an
+    /// `If-None-Match`, `If-Modified-Since`, `If-Match`, `If-Unmodified-Since`
+    /// or `If-Range` header.
+    ///
+    /// For these requests `http.response.not_modified` tells if the response
+    /// was a 304, to see how much of the revalidation traffic hits the cache.
+    #[must_use]
+    pub fn with_conditional_requests(mut self) -> Self {
+        self.conditional_requests = true;
+        self
+    }
+
+    /// Record the calling service from `header` as `peer.service`, eg. for
+    /// `X-Caller-Service`.
+    ///
+    /// To keep the number of values bounded, the value is lowercased and only
+    /// recorded when it is at most 64 ASCII letters, digits, `.`, `_` and `-`.
+    /// Clients can send any value, so it should only be relied on for service
+    /// maps. Invalid header names disable the recording.
+    #[must_use]
+    pub fn with_peer_service_header(mut self, header: &str) -> Self {
+        self.peer_service_header = HeaderName::try_from(header.to_ascii_lowercase()).ok();
+        self
+    }
+
+    /// Record the request id in `header` as `request_id`, eg. `x-request-id`,
+    /// and return it in the same response header.
+    ///
+    /// A UUID v4 is generated when the request has no (valid) id or when `mode`
+    /// is [`RequestIdMode::AlwaysGenerate`], and set in the request header for
+    /// the handler. Support staff can then paste the id from the response into
+    /// Honeycomb. A response header set by the handler is kept. Invalid header
+    /// names disable the request id.
+    ///
+    /// With the `tower-http` feature, the id in the `tower_http::request_id::RequestId`
+    /// request extension is used whatever the `mode`, so a `SetRequestIdLayer` in
+    /// front of this layer decides the id.
+    #[must_use]
+    pub fn with_request_id(mut self, header: &str, mode: RequestIdMode) -> Self {
+        self.request_id = HeaderName::try_from(header.to_ascii_lowercase())
+            .ok()
+            .map(|header| (header, mode));
+        self
+    }
+
+    /// Add the span events `request.received` when the request arrives and
+    /// `response.sent` when the response is handed back to axum.
+    ///
+    /// They show the request lifecycle in Honeycomb's trace waterfall. This is
+    /// off by default, as it adds two events to every span.
+    #[must_use]
+    pub fn with_lifecycle_events(mut self) -> Self {
+        self.lifecycle_events = true;
+        self
+    }
+
+    /// Record claims from the [`JwtClaims`] extension as span attributes.
+    ///
+    /// `mapping` is a list of `(claim, attribute)` pairs, eg.
+    /// ```
+    /// # use axum_otlp_honeycomb::opentelemetry_tracing_layer;
+    /// let layer = opentelemetry_tracing_layer()
+    ///     .with_claim_attributes([("sub", "enduser.id"), ("tenant", "tenant.id")]);
+    /// ```
+    /// Claims missing from the extension are not recorded.
+    #[must_use]
+    pub fn with_claim_attributes<I, C, A>(mut self, mapping: I) -> Self
+    where
+        I: IntoIterator<Item = (C, A)>,
+        C: Into<String>,
+        A: Into<String>,
+    {
+        self.claim_attributes = mapping
+            .into_iter()
+            .map(|(claim, attribute)| (claim.into(), attribute.into()))
+            .collect();
+        self
+    }
+
+    /// Set the attribute the [`OperationId`] extension is recorded as,
+    /// the default is `operation.id`.
+    #[must_use]
+    pub fn with_operation_id_attribute(mut self, attribute: impl Into<String>) -> Self {
+        self.operation_id_attribute = Some(attribute.into());
+        self
+    }
+
+    /// Get the SNI hostname recorded as `tls.client.server_name` from the request
+    /// extensions with `server_name`, instead of from the [`TlsServerName`] extension.
+    ///
+    /// Nothing is recorded when `server_name` returns `None`.
+    #[must_use]
+    pub fn with_tls_server_name<F>(mut self, server_name: F) -> Self
+    where
+        F: Fn(&http::Extensions) -> Option<String> + Send + Sync + 'static,
+    {
+        self.tls_server_name = Some(Hook(Arc::new(server_name)));
+        self
+    }
+
+    /// Get the HTTP/2 stream id recorded as `http2.stream_id` from the request
+    /// extensions with `stream_id`, instead of from the [`Http2StreamId`] extension.
+    ///
+    /// Nothing is recorded when `stream_id` returns `None`.
+    #[must_use]
+    pub fn with_http2_stream_id<F>(mut self, stream_id: F) -> Self
+    where
+        F: Fn(&http::Extensions) -> Option<u32> + Send + Sync + 'static,
+    {
+        self.http2_stream_id = Some(Hook(Arc::new(stream_id)));
+        self
+    }
+
+    /// Record the values returned by `snapshot` on the span when the response is ready,
+    /// eg. `[("db.pool.idle", 3), ("db.pool.size", 10)]`.
+    ///
+    /// This shows resource saturation directly on the traces. The snapshot is
+    /// taken for every request, so it must be cheap and must not block.
+    #[must_use]
+    pub fn with_resource_snapshot<F>(mut self, snapshot: F) -> Self
+    where
+        F: Fn() -> Vec<(&'static str, i64)> + Send + Sync + 'static,
+    {
+        self.resource_snapshot = Some(Hook(Arc::new(snapshot)));
+        self
+    }
+
+    /// Record the time the request waited in a proxy queue as
+    /// `http.request.queue_time_ms`, from the start timestamp in `header`.
+    ///
+    /// `header` is usually `x-request-start`, as set by nginx, `HAProxy` or Heroku.
+    /// The value may be prefixed with `t=` and be in seconds (with decimals),
+    /// milliseconds or microseconds since the Unix epoch. Absent or malformed
+    /// values, and start times in the future, are ignored. Invalid header names
+    /// disable the recording.
+    #[must_use]
+    pub fn with_request_start_header(mut self, header: &str) -> Self {
+        self.request_start_header = HeaderName::try_from(header.to_ascii_lowercase()).ok();
+        self
+    }
+
+    /// The response headers to record as `http.ratelimit.limit` and
+    /// `http.ratelimit.remaining`.
+    ///
+    /// The default is `x-ratelimit-limit` and `x-ratelimit-remaining`. Header
+    /// names are matched case-insensitively, if one is invalid the defaults are kept.
+    /// Absent or non-numeric values are not recorded.
+    #[must_use]
+    pub fn with_rate_limit_headers(mut self, limit: &str, remaining: &str) -> Self {
+        if let (Ok(limit), Ok(remaining)) = (
+            HeaderName::try_from(limit.to_ascii_lowercase()),
+            HeaderName::try_from(remaining.to_ascii_lowercase()),
+        ) {
+            self.rate_limit_headers = Some((limit, remaining));
+        }
+        self
+    }
+
+    /// Record the metrics `http.server.request.count`,
+    /// `http.server.request.duration` (in seconds),
+    /// `http.server.active_requests` and, when the `Content-Length` is known,
+    /// `http.server.request.body.size` and `http.server.response.body.size`
+    /// (in bytes) for every request.
+    ///
+    /// They have the attributes `http.request.method`, `http.route` and
+    /// (except the active requests) `http.response.status_code`, and are
+    /// exported by the meter provider from `init_otlp_metrics()`. To keep the
+    /// number of attribute values bounded, requests without a matched route
+    /// (eg. 404s) have the `http.route` `<unmatched>` and unknown methods are
+    /// recorded as `_OTHER`. They are recorded for all requests, also the ones
+    /// without a span because of sampling or `with_span_rate_limit`.
+    #[must_use]
+    pub fn with_metrics(mut self) -> Self {
+        self.http_metrics = Some(Arc::new(HttpMetrics::default()));
+        self
+    }
+
+    /// Log an ERROR event in the request span when it ends in error, ie. with
+    /// a 5xx status, a failure of the service, a panic or a failing response body.
+    ///
+    /// This shows the failed requests in Honeycomb's logs, correlated with the
+    /// trace. When the handler already logged an ERROR event in the request,
+    /// the log layer from `init_otlp_log_layer()` drops this one.
+    #[must_use]
+    pub fn with_error_logs(mut self) -> Self {
+        self.error_logs = true;
+        self
+    }
+
+    /// Record the location of panics in the handlers as `exception.stacktrace`.
+    ///
+    /// Panics are always recorded on the request span with `otel.status_code`
+    /// `ERROR`, `http.response.status_code` 500 and the panic message as
+    /// `exception.message`, and then passed on, eg. to a `CatchPanicLayer`
+    /// above this layer. The location is only known to the panic hook, so
+    /// this installs a panic hook that remembers it and then calls the previous
+    /// hook. Install other panic hooks before creating the layer.
+    #[must_use]
+    pub fn with_panic_locations(mut self) -> Self {
+        panic::install_location_hook();
+        self.panic_locations = true;
+        self
+    }
+
+    /// Record the attributes computed by `attributes` from both the request
+    /// and the response, eg. the outcome of an idempotent request.
+    ///
+    /// The request parts (without the body) are copied when the request arrives,
+    /// and `attributes` is called with them when the response is ready. It is
+    /// not called when the service fails without a response.
+    #[must_use]
+    pub fn with_request_response_attributes<F>(mut self, attributes: F) -> Self
+    where
+        F: Fn(&http::request::Parts, &http::response::Parts) -> Vec<(String, String)>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.request_response_attributes = Some(Hook(Arc::new(attributes)));
+        self
+    }
+
+    /// Record the user id returned by `extractor` as `user.id`, eg. from an
+    /// extension inserted by the authentication layer (which must be above
+    /// this one).
+    ///
+    /// When `extractor` returns `None`, `user.id` stays `-` and can still be
+    /// recorded by the handler. It is called when the request span has been
+    /// created, so it must be cheap and must not block.
+    #[must_use]
+    pub fn with_user_id_extractor<F>(mut self, extractor: F) -> Self
+    where
+        F: Fn(&http::request::Parts) -> Option<String> + Send + Sync + 'static,
+    {
+        self.user_id_extractor = Some(Hook(Arc::new(extractor)));
+        self
+    }
+
+    /// Record a coarse category of the `User-Agent` header as `user_agent.category`:
+    /// `bot`, `browser`, `mobile` or `api`, see [`user_agent_category`].
+    ///
+    /// `user_agent.original` is still recorded, the category is a facet with few
+    /// values for dashboards.
+    #[must_use]
+    pub fn with_user_agent_category(self) -> Self {
+        self.with_user_agent_classifier(|user_agent| {
+            user_agent_category(user_agent).map(str::to_string)
+        })
+    }
+
+    /// Record the category returned by `classify` for the `User-Agent` header as
+    /// `user_agent.category`, instead of the built-in [`user_agent_category`].
+    ///
+    /// Nothing is recorded when `classify` returns `None`. It is called for
+    /// every request, so it must be cheap, and should return few distinct values.
+    #[must_use]
+    pub fn with_user_agent_classifier<F>(mut self, classify: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        self.user_agent_classifier = Some(Hook(Arc::new(classify)));
+        self
+    }
+
+    /// Record the feature flags returned by `flags` for the request as
+    /// `feature_flag.<name>` attributes, eg. `("new-checkout", "on")`.
+    ///
+    /// This allows slicing the traces by flag state in Honeycomb. Each flag is
+    /// a column, so only return the flags worth querying. `flags` is called
+    /// when the request span has been created, so it must be cheap and must
+    /// not block.
+    #[must_use]
+    pub fn with_feature_flags<F>(mut self, flags: F) -> Self
+    where
+        F: Fn(&http::request::Parts) -> Vec<(String, String)> + Send + Sync + 'static,
+    {
+        self.feature_flags = Some(Hook(Arc::new(flags)));
+        self
+    }
+
+    /// Don't create spans for requests to `paths`, eg. `["/healthz", "/metrics"]`.
+    ///
+    /// The paths are compared with the matched route (eg. `/users/{id}`) when
+    /// the router gives one, otherwise with the request path. The requests are
+    /// passed on without a span, but an incoming trace context is still made
+    /// current while the request is handled.
+    #[must_use]
+    pub fn with_skip_paths<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.skip_paths.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Don't create spans for requests where `skip` returns true, like
+    /// `with_skip_paths` but with a predicate on the route or path.
+    #[must_use]
+    pub fn with_skip_predicate<F>(mut self, skip: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.skip_predicate = Some(Hook(Arc::new(skip)));
+        self
+    }
+
+    fn is_skipped<B>(&self, req: &Request<B>) -> bool {
+        if self.skip_paths.is_empty() && self.skip_predicate.is_none() {
+            return false;
+        }
+        let route = match http_route(req) {
+            "" => req.uri().path(),
+            route => route,
+        };
+        self.skip_paths.contains(route)
+            || self.skip_predicate.as_ref().is_some_and(|skip| skip(route))
+    }
+
+    /// Take `client.address` from the `Forwarded` or `X-Forwarded-For` header,
+    /// using the `entry` given.
+    ///
+    /// Only use this behind a proxy that sets the header, as clients can send
+    /// any value. Without forwarded headers, and when this is not set,
+    /// `client.address` is the peer address from axum's `ConnectInfo<SocketAddr>`
+    /// (see `Router::into_make_service_with_connect_info`).
+    #[must_use]
+    pub fn with_trusted_forwarded_headers(mut self, entry: ForwardedFor) -> Self {
+        self.forwarded_for = Some(entry);
+        self
+    }
+
+    /// Remove more headers from the `http.headers` span field.
+    ///
+    /// `authorization`, `cookie` and any header whose name contains `token` are
+    /// always removed, the given headers are removed as well. Header names are
+    /// matched case-insensitively, invalid names are ignored.
+    ///
+    /// ```
+    /// # use axum_otlp_honeycomb::opentelemetry_tracing_layer;
+    /// let layer = opentelemetry_tracing_layer().with_redacted_headers(["cookie", "x-api-key"]);
+    /// ```
+    #[must_use]
+    pub fn with_redacted_headers<I, N>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: AsRef<str>,
+    {
+        Arc::make_mut(&mut self.redacted_headers).extend(
+            names
+                .into_iter()
+                .filter_map(|name| HeaderName::try_from(name.as_ref().to_ascii_lowercase()).ok()),
+        );
+        self
+    }
+
+    /// Record a hash of the idempotency key in `header` as
+    /// `http.request.idempotency_key.hash`, eg. for `Idempotency-Key`.
+    ///
+    /// Retries of a request get the same hash, so they can be found in
+    /// Honeycomb without storing the key. The header itself is redacted. The
+    /// default hash is not cryptographic and may change with the Rust version,
+    /// see [`with_idempotency_key_hasher`](Self::with_idempotency_key_hasher).
+    /// Invalid header names disable the recording.
+    #[must_use]
+    pub fn with_idempotency_key(mut self, header: &str) -> Self {
+        self.idempotency_key_header = HeaderName::try_from(header.to_ascii_lowercase()).ok();
+        if let Some(header) = &self.idempotency_key_header {
+            Arc::make_mut(&mut self.redacted_headers).insert(header.clone());
+        }
+        self
+    }
+
+    /// Hash the idempotency key with `hasher`, eg. a keyed SHA-256 when the keys
+    /// are guessable.
+    #[must_use]
+    pub fn with_idempotency_key_hasher<F>(mut self, hasher: F) -> Self
+    where
+        F: Fn(&[u8]) -> String + Send + Sync + 'static,
+    {
+        self.idempotency_key_hasher = Some(Hook(Arc::new(hasher)));
+        self
+    }
+
+    /// Record the request headers in `names` as separate span attributes,
+    /// eg. `content-type` as `http.request.header.content_type`.
+    ///
+    /// This is the recommended way to record headers, as Honeycomb can filter
+    /// and group by each attribute. The names are lowercased and `-` becomes `_`.
+    /// Several values of a header are joined by `,`. Absent and redacted headers
+    /// are not recorded, and invalid header names are skipped.
+    ///
+    /// The `http.headers` field with all the headers in one string is then left
+    /// out, unless [`with_headers_blob()`](Self::with_headers_blob) is used.
+    #[must_use]
+    pub fn with_request_headers<I, N>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: AsRef<str>,
+    {
+        self.request_headers = names
+            .into_iter()
+            .filter_map(|name| HeaderName::try_from(name.as_ref().to_ascii_lowercase()).ok())
+            .map(|name| {
+                let attribute = format!("http.request.header.{}", name.as_str().replace('-', "_"));
+                (name, attribute)
+            })
+            .collect();
+        self
+    }
+
+    /// Keep the `http.headers` field with all the (non-redacted) headers when
+    /// [`with_request_headers()`](Self::with_request_headers) is used.
+    #[must_use]
+    pub fn with_headers_blob(mut self) -> Self {
+        self.headers_blob = true;
+        self
+    }
+
+    /// Cut string values longer than `max_len` bytes, the default is 4096.
+    ///
+    /// This applies to `http.headers`, `url.path`, `url.query`,
+    /// `user_agent.original`, the headers of `with_request_headers` and the
+    /// parameters of `with_path_params`. The values are cut at a character
+    /// boundary and end with `…`, so they are at most `max_len` bytes. Below 3
+    /// bytes there is no room for the `…`.
+    #[must_use]
+    pub fn with_max_attribute_length(mut self, max_len: usize) -> Self {
+        self.max_attribute_length = Some(max_len);
+        self
+    }
+
+    /// Record `<attribute>.truncated = true` next to the values cut by
+    /// [`with_max_attribute_length()`](Self::with_max_attribute_length).
+    #[must_use]
+    pub fn with_truncation_markers(mut self) -> Self {
+        self.truncation_markers = true;
+        self
+    }
+
+    /// The length of `with_max_attribute_length`, or the default
+    fn max_attribute_length(&self) -> usize {
+        self.max_attribute_length
+            .unwrap_or(DEFAULT_MAX_ATTRIBUTE_LENGTH)
+    }
+
+    /// Record `<key>.truncated` if the value was cut and the markers are on
+    fn mark_truncated(&self, span: &Span, key: &str, truncated: bool) {
+        if self.truncation_markers && truncated {
+            span.set_attribute(format!("{key}.truncated"), true);
+        }
+    }
+
+    fn is_redacted(&self, name: &HeaderName) -> bool {
+        name == http::header::AUTHORIZATION
+            || name == http::header::COOKIE
+            || name.as_str().contains("token")
+            || self.redacted_headers.contains(name)
+    }
+
+    /// Add the current values of `attributes` to every request span.
+    #[must_use]
+    pub fn with_dynamic_attributes(mut self, attributes: DynamicAttributes) -> Self {
+        self.dynamic_attributes = Some(attributes);
+        self
+    }
 }
 
 impl<S> tower::Layer<S> for AxumOtelLayer {
@@ -48,7 +1082,7 @@ impl<S> tower::Layer<S> for AxumOtelLayer {
     type Service = AxumOtelService<S>;
     fn layer(&self, inner: S) -> Self::Service {
         AxumOtelService {
-            extract_parent: self.extract_parent,
+            layer: Arc::new(self.clone()),
             inner,
         }
     }
@@ -56,7 +1090,7 @@ impl<S> tower::Layer<S> for AxumOtelLayer {
 
 #[derive(Debug, Clone)]
 pub struct AxumOtelService<S> {
-    extract_parent: bool,
+    layer: Arc<AxumOtelLayer>,
     inner: S,
 }
 
@@ -66,8 +1100,9 @@ where
     S::Error: Error + 'static, //fmt::Display + 'static,
     S::Future: Send + 'static,
     B: Send + 'static,
+    B2: http_body::Body,
 {
-    type Response = S::Response;
+    type Response = Response<ResponseBody<B2>>;
     type Error = S::Error;
     type Future = ResponseFuture<S::Future>;
 
@@ -80,8 +1115,72 @@ where
     /// on response
     fn call(&mut self, req: Request<B>) -> Self::Future {
         let start = Instant::now();
-        let req = req;
-        let span = make_span(&req, self.extract_parent);
+        let mut req = req;
+        if let Some((header, mode)) = &self.layer.request_id {
+            match tower_request_id(req.extensions()) {
+                Some(id) => {
+                    req.headers_mut().insert(header.clone(), id);
+                }
+                None => ensure_request_id(req.headers_mut(), header, *mode),
+            }
+        }
+        if self.layer.is_skipped(&req) {
+            // no span, but keep the trace of the caller going
+            let parent = (self.layer.extract_parent && req.headers().contains_key("traceparent"))
+                .then(|| extract_context(&req));
+            let request = RequestInfo::new(&req, &self.layer);
+            let active = request.start_metrics(&self.layer);
+            let future = {
+                let _context = parent.clone().map(opentelemetry::Context::attach);
+                self.inner.call(req)
+            };
+            return ResponseFuture {
+                inner: future,
+                layer: Arc::clone(&self.layer),
+                span: Span::none(),
+                start,
+                request,
+                parent,
+                active,
+            };
+        }
+        let mut request = RequestInfo::new(&req, &self.layer);
+        let span = match &self.layer.span_rate_limit {
+            Some(limiter) if !limiter.try_acquire() => Span::none(),
+            _ => make_span(&req, &self.layer, request.normalized_route.as_deref()),
+        };
+        if self.layer.lifecycle_events && !span.is_none() {
+            span.add_event("request.received", Vec::new());
+        }
+        if let Some(attributes) = &self.layer.dynamic_attributes {
+            attributes.for_each(|key, value| span.set_attribute(key.clone(), value.clone()));
+        }
+
+        if !span.is_none()
+            && (self.layer.on_span_start.is_some()
+                || self.layer.request_response_attributes.is_some()
+                || self.layer.feature_flags.is_some()
+                || self.layer.user_id_extractor.is_some())
+        {
+            let (parts, body) = req.into_parts();
+            if let Some(extractor) = &self.layer.user_id_extractor
+                && let Some(user_id) = extractor(&parts)
+            {
+                span.record("user.id", user_id);
+            }
+            if let Some(flags) = &self.layer.feature_flags {
+                for (name, value) in flags(&parts) {
+                    span.set_attribute(format!("feature_flag.{name}"), value);
+                }
+            }
+            if let Some(hook) = &self.layer.on_span_start {
+                hook(&span, &parts);
+            }
+            if self.layer.request_response_attributes.is_some() {
+                request.parts = Some(parts.clone());
+            }
+            req = Request::from_parts(parts, body);
+        }
 
         let future = {
             // should this be a call to instrument() instead of enter() ??
@@ -91,53 +1190,442 @@ where
         };
         ResponseFuture {
             inner: future,
+            layer: Arc::clone(&self.layer),
             span,
             start,
+            active: request.start_metrics(&self.layer),
+            request,
+            parent: None,
         }
     }
 }
 
-/// Create a tracing-span from a Request
-fn make_span<B>(req: &Request<B>, extract_parent: bool) -> Span {
-    let route = http_route(req);
+/// Create a tracing-span from a Request, `normalized_route` is the route from
+/// `with_route_normalizer` if it differs from the matched route
+fn make_span<B>(req: &Request<B>, layer: &AxumOtelLayer, normalized_route: Option<&str>) -> Span {
+    let raw_route = http_route(req);
+    let route = normalized_route.unwrap_or(raw_route);
     let method = req.method().as_str();
+    let max_len = layer.max_attribute_length();
+    let mut headers =
+        (layer.request_headers.is_empty() || layer.headers_blob).then(|| headers(req, layer));
+    let headers_truncated = headers
+        .as_mut()
+        .is_some_and(|headers| truncate(headers, max_len));
+    let path = truncate_str(req.uri().path(), max_len);
+    let query = req.uri().query().map(|query| truncate_str(query, max_len));
+    let user_agent_original = truncate_str(user_agent(req), max_len);
 
     let span = info_span!(
-        "HTTP request",
+        REQUEST_SPAN_NAME,
+        error.source = Empty, // to be set on response
         exception.message = Empty, // to be set on response
-        http.headers = headers(req),
+        http.headers = headers,
+        http.method_not_allowed = Empty, // to be set on response
+        http.request.body.size = content_length(req.headers()),
+        http.request.content_encoding = content_encoding(req.headers()),
         http.request.method = method,
+        http.response.body.size = Empty, // to be set on response
         http.response.status_code = Empty, // to be set on response
         http.route = route,
-        otel.kind = ?opentelemetry::trace::SpanKind::Server,
-        otel.name = format!("{method} {route}"),
+        http.route.raw = normalized_route.is_some().then_some(raw_route),
+        client.address = client_address(req, layer),
+        otel.kind = ?layer.span_kind(raw_route),
+        otel.name = Empty, // set below unless omitted
         otel.status_code = Empty, // to be set on response
+        request_id = request_id(req, layer),
+        network.protocol.version = http_flavor(req.version()),
         server.address = http_host(req),
+        server.port = server_port(req),
         trace_id = Empty, // to be set on response
-        url.path = req.uri().path(),
-        url.query = req.uri().query(),
+        url.path = path.as_ref(),
+        url.query = query.as_deref(),
         user.id = "-", // to be set when/if user-id is found
-        user_agent.original = user_agent(req),
+        user_agent.original = user_agent_original.as_ref(),
+    );
+    layer.mark_truncated(&span, "http.headers", headers_truncated);
+    layer.mark_truncated(&span, "url.path", matches!(path, Cow::Owned(_)));
+    layer.mark_truncated(&span, "url.query", matches!(query, Some(Cow::Owned(_))));
+    layer.mark_truncated(
+        &span,
+        "user_agent.original",
+        matches!(user_agent_original, Cow::Owned(_)),
     );
-    if extract_parent {
+    if let Some(SpanName(name)) = req.extensions().get::<SpanName>() {
+        span.record("otel.name", name.as_str());
+    } else if !layer.omit_otel_name {
+        span.record("otel.name", span_name(req, layer, method, route));
+    }
+    record_resend_count(&span, req.extensions());
+    record_claims(&span, layer, req.extensions());
+    record_operation_id(&span, layer, req.extensions());
+    record_tls_server_name(&span, layer, req.extensions());
+    record_http2_stream_id(&span, layer, req.extensions());
+    if let Some(service) = peer_service(req, layer) {
+        span.set_attribute("peer.service", service);
+    }
+    if let Some(classify) = &layer.user_agent_classifier
+        && let Some(category) = classify(user_agent(req))
+    {
+        span.set_attribute("user_agent.category", category);
+    }
+    record_queue_time(&span, layer, req.headers());
+    record_idempotency_key(&span, layer, req.headers());
+    record_request_headers(&span, layer, req.headers());
+    record_path_params(&span, layer, raw_route, req.uri().path());
+    for (i, segment) in route
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .take(layer.route_segments)
+        .enumerate()
+    {
+        span.set_attribute(format!("http.route.segment.{i}"), segment.to_string());
+    }
+    if let Some(dataset) = &layer.honeycomb_dataset {
+        span.set_attribute("honeycomb.dataset", dataset.clone());
+    }
+    if layer.conditional_requests && is_conditional(req.headers()) {
+        span.set_attribute("http.request.conditional", true);
+    }
+    if layer.start_timestamp
+        && let Ok(since_epoch) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+    {
+        span.set_attribute(
+            "request.start_unix_ms",
+            i64::try_from(since_epoch.as_millis()).unwrap_or(i64::MAX),
+        );
+    }
+    if let Some((color, canary)) = &layer.deployment {
+        span.set_attribute("deployment.color", color.clone());
+        span.set_attribute("deployment.canary", *canary);
+    }
+    if let Some(mode) = layer.accept_language
+        && let Some(language) = accept_language(req, mode)
+    {
+        span.set_attribute("http.request.header.accept_language", language);
+    }
+    if layer.extract_parent {
         span.set_parent(extract_context(req)).ok();
     }
     span
 }
 
+/// The `otel.name` of the request span
+fn span_name<B>(req: &Request<B>, layer: &AxumOtelLayer, method: &str, route: &str) -> String {
+    if layer.streaming_span_names {
+        if is_websocket(req) {
+            return format!("WS {route}");
+        }
+        if is_event_stream(req) {
+            return format!("SSE {route}");
+        }
+    }
+    layer.otel_name(method, route)
+}
+
+fn is_websocket<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get(http::header::UPGRADE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.eq_ignore_ascii_case("websocket"))
+}
+
+fn is_event_stream<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get(http::header::ACCEPT)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.contains("text/event-stream"))
+}
+
+/// Get the `Accept-Language` header, or the preferred primary language from it
+fn accept_language<B>(req: &Request<B>, mode: AcceptLanguage) -> Option<String> {
+    let header = req
+        .headers()
+        .get(http::header::ACCEPT_LANGUAGE)?
+        .to_str()
+        .ok()?;
+    match mode {
+        AcceptLanguage::Full => Some(header.to_string()),
+        AcceptLanguage::Primary => primary_language(header),
+    }
+}
+
+/// The primary subtag of the language with the highest weight, the first one
+/// wins a tie. `*`, entries with `q=0` and malformed weights are skipped.
+fn primary_language(header: &str) -> Option<String> {
+    let mut preferred: Option<(&str, f32)> = None;
+    for entry in header.split(',') {
+        let mut parts = entry.split(';');
+        let tag = parts.next().unwrap_or_default().trim();
+        let weight = parts.find_map(|p| {
+            let (name, value) = p.split_once('=')?;
+            name.trim().eq_ignore_ascii_case("q").then_some(value)
+        });
+        let quality = weight.map_or(Ok(1.0), |q| q.trim().parse::<f32>());
+        let Ok(quality) = quality else {
+            continue;
+        };
+        if tag.is_empty() || tag == "*" || quality <= 0.0 || quality > 1.0 || quality.is_nan() {
+            continue;
+        }
+        if preferred.is_none_or(|(_, q)| quality > q) {
+            preferred = Some((tag, quality));
+        }
+    }
+    let (tag, _) = preferred?;
+    let primary = tag.split('-').next().unwrap_or(tag);
+    Some(primary.to_ascii_lowercase())
+}
+
+fn record_resend_count(span: &Span, extensions: &http::Extensions) {
+    if let Some(ResendCount(count)) = extensions.get::<ResendCount>()
+        && *count > 0
+    {
+        span.set_attribute("http.request.resend_count", i64::from(*count));
+    }
+}
+
+fn record_claims(span: &Span, layer: &AxumOtelLayer, extensions: &http::Extensions) {
+    if layer.claim_attributes.is_empty() {
+        return;
+    }
+    if let Some(JwtClaims(claims)) = extensions.get::<JwtClaims>() {
+        for (claim, attribute) in &layer.claim_attributes {
+            if let Some(value) = claims.get(claim) {
+                span.set_attribute(attribute.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn record_operation_id(span: &Span, layer: &AxumOtelLayer, extensions: &http::Extensions) {
+    if let Some(OperationId(id)) = extensions.get::<OperationId>() {
+        let attribute = layer
+            .operation_id_attribute
+            .clone()
+            .unwrap_or_else(|| "operation.id".to_string());
+        span.set_attribute(attribute, id.clone());
+    }
+}
+
+fn record_tls_server_name(span: &Span, layer: &AxumOtelLayer, extensions: &http::Extensions) {
+    let server_name = match &layer.tls_server_name {
+        Some(server_name) => server_name(extensions),
+        None => extensions
+            .get::<TlsServerName>()
+            .map(|TlsServerName(name)| name.clone()),
+    };
+    if let Some(server_name) = server_name {
+        span.set_attribute("tls.client.server_name", server_name);
+    }
+}
+
+/// Record the parameters of `route` from `path`, for `with_path_params`
+fn record_path_params(span: &Span, layer: &AxumOtelLayer, route: &str, path: &str) {
+    let Some(prefix) = &layer.path_params_prefix else {
+        return;
+    };
+    let record = |name: &str, value: &str| {
+        let key = format!("{prefix}.{name}");
+        if name.contains("token") || layer.redacted_path_params.contains(name) {
+            span.set_attribute(key, "[REDACTED]");
+            return;
+        }
+        let value = truncate_str(value, layer.max_attribute_length());
+        layer.mark_truncated(span, &key, matches!(value, Cow::Owned(_)));
+        span.set_attribute(key, value.into_owned());
+    };
+    let mut segments = path.split('/');
+    for template in route.split('/') {
+        let Some(segment) = segments.next() else {
+            return;
+        };
+        let Some(name) = template
+            .strip_prefix('{')
+            .and_then(|name| name.strip_suffix('}'))
+        else {
+            continue;
+        };
+        if let Some(name) = name.strip_prefix('*') {
+            let rest: Vec<&str> = std::iter::once(segment).chain(segments).collect();
+            record(name, &rest.join("/"));
+            return;
+        }
+        record(name, segment);
+    }
+}
+
+fn record_http2_stream_id(span: &Span, layer: &AxumOtelLayer, extensions: &http::Extensions) {
+    let stream_id = match &layer.http2_stream_id {
+        Some(stream_id) => stream_id(extensions),
+        None => extensions
+            .get::<Http2StreamId>()
+            .map(|Http2StreamId(id)| *id),
+    };
+    if let Some(stream_id) = stream_id {
+        span.set_attribute("http2.stream_id", i64::from(stream_id));
+    }
+}
+
+fn record_rate_limit<B>(span: &Span, layer: &AxumOtelLayer, response: &http::Response<B>) {
+    if let Some(RateLimit { limit, remaining }) = response.extensions().get::<RateLimit>() {
+        span.set_attribute("http.ratelimit.limit", saturating_i64(*limit));
+        span.set_attribute("http.ratelimit.remaining", saturating_i64(*remaining));
+        return;
+    }
+    let (limit, remaining) = match &layer.rate_limit_headers {
+        Some((limit, remaining)) => (limit.as_str(), remaining.as_str()),
+        None => ("x-ratelimit-limit", "x-ratelimit-remaining"),
+    };
+    for (header, attribute) in [
+        (limit, "http.ratelimit.limit"),
+        (remaining, "http.ratelimit.remaining"),
+    ] {
+        if let Some(value) = response
+            .headers()
+            .get(header)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<i64>().ok())
+        {
+            span.set_attribute(attribute, value);
+        }
+    }
+}
+
+fn saturating_i64(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+fn record_queue_time(span: &Span, layer: &AxumOtelLayer, headers: &HeaderMap) {
+    let Some(header) = &layer.request_start_header else {
+        return;
+    };
+    if let Some(queue_time) = headers
+        .get(header)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| queue_time(value, SystemTime::now()))
+    {
+        span.set_attribute(
+            "http.request.queue_time_ms",
+            queue_time.as_secs_f64() * 1000.0,
+        );
+    }
+}
+
+fn record_idempotency_key(span: &Span, layer: &AxumOtelLayer, headers: &HeaderMap) {
+    let Some(key) = layer
+        .idempotency_key_header
+        .as_ref()
+        .and_then(|header| headers.get(header))
+    else {
+        return;
+    };
+    let hash = match &layer.idempotency_key_hasher {
+        Some(hasher) => hasher(key.as_bytes()),
+        None => {
+            let mut hasher = DefaultHasher::new();
+            hasher.write(key.as_bytes());
+            format!("{:016x}", hasher.finish())
+        }
+    };
+    span.set_attribute("http.request.idempotency_key.hash", hash);
+}
+
+/// The time from the request start timestamp `value` until `now`, `None` if
+/// it can't be parsed or is in the future
+fn queue_time(value: &str, now: SystemTime) -> Option<Duration> {
+    now.duration_since(parse_request_start(value)?).ok()
+}
+
+/// Parse a request start timestamp like `t=1609459200.123`, `1609459200123`
+/// or `t=1609459200123456`, guessing the unit from the magnitude.
+fn parse_request_start(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+    let value = value.strip_prefix("t=").unwrap_or(value);
+    let number: f64 = value.parse().ok()?;
+    // seconds until the year 5138, then milliseconds, then microseconds
+    let seconds = if number >= 1e14 {
+        number / 1e6
+    } else if number >= 1e11 {
+        number / 1e3
+    } else {
+        number
+    };
+    let since_epoch = Duration::try_from_secs_f64(seconds).ok()?;
+    SystemTime::UNIX_EPOCH.checked_add(since_epoch)
+}
+
 /// Get (and filter) request headers
-fn headers<B>(req: &Request<B>) -> String {
+fn headers<B>(req: &Request<B>, layer: &AxumOtelLayer) -> String {
     let filtered_headers: HeaderMap<HeaderValue> = req
         .headers()
         .iter()
-        .filter(|(name, _)| {
-            *name != "authorization" && *name != "cookie" && !name.as_str().contains("token")
-        })
+        .filter(|(name, _)| !layer.is_redacted(name))
         .map(|(n, v)| (n.clone(), v.clone()))
         .collect();
     format!("{filtered_headers:#?}")
 }
 
+/// The default of `with_max_attribute_length`
+pub(crate) const DEFAULT_MAX_ATTRIBUTE_LENGTH: usize = 4096;
+
+/// Appended to the values that were cut
+const TRUNCATION_MARKER: &str = "…";
+
+/// Cut `value` to at most `max_len` bytes including the marker, returns whether it was cut
+pub(crate) fn truncate(value: &mut String, max_len: usize) -> bool {
+    let Some((end, marker)) = truncation_end(value, max_len) else {
+        return false;
+    };
+    value.truncate(end);
+    value.push_str(marker);
+    true
+}
+
+/// `value` cut like `truncate()`, only allocating when it is cut
+fn truncate_str(value: &str, max_len: usize) -> Cow<'_, str> {
+    match truncation_end(value, max_len) {
+        Some((end, marker)) => Cow::Owned(format!("{}{marker}", &value[..end])),
+        None => Cow::Borrowed(value),
+    }
+}
+
+/// Where to cut `value` and the marker to append, `None` if it fits. The
+/// marker is left out when `max_len` has no room for it.
+fn truncation_end(value: &str, max_len: usize) -> Option<(usize, &'static str)> {
+    if value.len() <= max_len {
+        return None;
+    }
+    let (mut end, marker) = match max_len.checked_sub(TRUNCATION_MARKER.len()) {
+        Some(end) => (end, TRUNCATION_MARKER),
+        None => (max_len, ""),
+    };
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some((end, marker))
+}
+
+/// Record the headers of `with_request_headers()` as separate attributes
+fn record_request_headers(span: &Span, layer: &AxumOtelLayer, headers: &HeaderMap) {
+    for (name, attribute) in &layer.request_headers {
+        if layer.is_redacted(name) {
+            continue;
+        }
+        let values: Vec<&str> = headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        if !values.is_empty() {
+            let mut value = values.join(",");
+            let truncated = truncate(&mut value, layer.max_attribute_length());
+            span.set_attribute(attribute.clone(), value);
+            layer.mark_truncated(span, attribute, truncated);
+        }
+    }
+}
+
 #[inline]
 fn http_route<B>(req: &Request<B>) -> &str {
     req.extensions()
@@ -154,12 +1642,282 @@ fn http_host<B>(req: &http::Request<B>) -> &str {
 }
 
 #[inline]
+/// The address of the client, from the trusted forwarded headers or the connection
+fn client_address<B>(req: &Request<B>, layer: &AxumOtelLayer) -> Option<String> {
+    layer
+        .forwarded_for
+        .and_then(|entry| forwarded_address(req.headers(), entry))
+        .or_else(|| {
+            req.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string())
+        })
+}
+
+/// The client address from the `Forwarded` header, or else `X-Forwarded-For`
+fn forwarded_address(headers: &HeaderMap, entry: ForwardedFor) -> Option<String> {
+    let forwarded = headers
+        .get_all(http::header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for").then_some(value)
+            })
+        });
+    let x_forwarded_for = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    let forwarded = pick_entry(forwarded, entry).map(client_of_entry);
+    let x_forwarded_for = pick_entry(x_forwarded_for, entry).map(client_of_entry);
+    let address = match (entry, forwarded, x_forwarded_for) {
+        (ForwardedFor::Leftmost, Some(address), _) | (_, Some(address), None) => address,
+        (_, None, Some(address)) => address,
+        // The client can send either header, and the proxy only appends to one
+        // of them, so the two are only trusted when they agree
+        (ForwardedFor::Rightmost, Some(forwarded), Some(x_forwarded_for)) => {
+            (forwarded == x_forwarded_for).then_some(forwarded)?
+        }
+        (_, None, None) => return None,
+    };
+    // `unknown` and obfuscated identifiers such as `_hidden` are not addresses
+    let obfuscated =
+        address.is_empty() || address.eq_ignore_ascii_case("unknown") || address.starts_with('_');
+    (!obfuscated).then(|| address.to_string())
+}
+
+/// The address of a forwarded entry, without quotes and port
+fn client_of_entry(entry: &str) -> &str {
+    strip_port(entry.trim().trim_matches('"'))
+}
+
+fn pick_entry<'a>(
+    mut entries: impl DoubleEndedIterator<Item = &'a str>,
+    entry: ForwardedFor,
+) -> Option<&'a str> {
+    match entry {
+        ForwardedFor::Leftmost => entries.next(),
+        ForwardedFor::Rightmost => entries.next_back(),
+    }
+}
+
+/// `192.0.2.1:4711` -> `192.0.2.1`, `[2001:db8::1]:4711` -> `2001:db8::1`
+fn strip_port(address: &str) -> &str {
+    if let Some(rest) = address.strip_prefix('[') {
+        return rest.split_once(']').map_or(rest, |(ip, _)| ip);
+    }
+    match address.split_once(':') {
+        // a single colon is a port, more are an IPv6 address
+        Some((ip, port)) if !port.contains(':') => ip,
+        _ => address,
+    }
+}
+
+/// The longest `peer.service` that is recorded
+const MAX_PEER_SERVICE_LEN: usize = 64;
+
+/// The calling service from the header of `with_peer_service_header()`, if it is valid
+fn peer_service<B>(req: &Request<B>, layer: &AxumOtelLayer) -> Option<String> {
+    let header = layer.peer_service_header.as_ref()?;
+    let value = req.headers().get(header)?.to_str().ok()?.trim();
+    let valid = !value.is_empty()
+        && value.len() <= MAX_PEER_SERVICE_LEN
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'));
+    valid.then(|| value.to_ascii_lowercase())
+}
+
+/// The longest request id that is accepted from the client
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Set a new request id in `header`, unless `mode` allows the one sent
+fn ensure_request_id(headers: &mut HeaderMap, header: &HeaderName, mode: RequestIdMode) {
+    let valid = headers.get(header).is_some_and(|value| {
+        !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN && value.to_str().is_ok()
+    });
+    if mode == RequestIdMode::AcceptIncoming && valid {
+        return;
+    }
+    if let Ok(value) = HeaderValue::try_from(new_request_id()) {
+        headers.insert(header.clone(), value);
+    }
+}
+
+/// The id set by a `tower_http` `SetRequestIdLayer` in front of this layer
+#[cfg(feature = "tower-http")]
+fn tower_request_id(extensions: &http::Extensions) -> Option<HeaderValue> {
+    extensions
+        .get::<tower_http::request_id::RequestId>()
+        .map(|id| id.header_value().clone())
+}
+
+#[cfg(not(feature = "tower-http"))]
+fn tower_request_id(_extensions: &http::Extensions) -> Option<HeaderValue> {
+    None
+}
+
+/// A random UUID v4
+fn new_request_id() -> String {
+    let mut bytes = RandomIdGenerator::default().new_trace_id().to_bytes();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[inline]
+fn request_id<'a, B>(req: &'a Request<B>, layer: &AxumOtelLayer) -> Option<&'a str> {
+    let (header, _) = layer.request_id.as_ref()?;
+    req.headers().get(header)?.to_str().ok()
+}
+
+/// The `Content-Encoding` of a compressed body, eg. `gzip`
+fn content_encoding(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(http::header::CONTENT_ENCODING)?
+        .to_str()
+        .ok()
+        .map(str::trim)
+        .filter(|encoding| !encoding.is_empty())
+}
+
+/// The request has one of the conditional headers
+fn is_conditional(headers: &HeaderMap) -> bool {
+    [
+        http::header::IF_NONE_MATCH,
+        http::header::IF_MODIFIED_SINCE,
+        http::header::IF_MATCH,
+        http::header::IF_UNMODIFIED_SINCE,
+        http::header::IF_RANGE,
+    ]
+    .iter()
+    .any(|header| headers.contains_key(header))
+}
+
+/// The body size from the `Content-Length` header, none for streamed bodies
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(http::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// The port from the URI, the `Host` header or the scheme
+fn server_port<B>(req: &Request<B>) -> Option<i64> {
+    req.uri()
+        .port_u16()
+        .or_else(|| {
+            req.headers()
+                .get(http::header::HOST)?
+                .to_str()
+                .ok()?
+                .parse::<http::uri::Authority>()
+                .ok()?
+                .port_u16()
+        })
+        .or_else(|| match req.uri().scheme_str() {
+            Some("http") => Some(80),
+            Some("https") => Some(443),
+            _ => None,
+        })
+        .map(i64::from)
+}
+
+/// The HTTP version as `network.protocol.version`, eg. `1.1` or `2`
+fn http_flavor(version: http::Version) -> &'static str {
+    match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        http::Version::HTTP_11 => "1.1",
+        http::Version::HTTP_2 => "2",
+        http::Version::HTTP_3 => "3",
+        _ => "other",
+    }
+}
+
 fn user_agent<B>(req: &http::Request<B>) -> &str {
     req.headers()
         .get(http::header::USER_AGENT)
         .map_or("", |h| h.to_str().unwrap_or(""))
 }
 
+/// The category of `user_agent`: `bot`, `browser`, `mobile` or `api`
+///
+/// User agents that name themselves a bot, crawler, spider or monitor are `bot`,
+/// headless browsers included. Other agents starting with `Mozilla/` or `Opera`
+/// are `mobile` when they mention a phone or tablet, else `browser`. The rest,
+/// eg. `curl`, `okhttp` or `reqwest`, are `api`. An empty user agent has no category.
+///
+/// ```
+/// # use axum_otlp_honeycomb::user_agent_category;
+/// assert_eq!(user_agent_category("Googlebot/2.1 (+http://www.google.com/bot.html)"), Some("bot"));
+/// assert_eq!(user_agent_category("Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0"), Some("browser"));
+/// assert_eq!(user_agent_category("Mozilla/5.0 (iPhone; CPU iPhone OS 17_5 like Mac OS X)"), Some("mobile"));
+/// assert_eq!(user_agent_category("curl/8.5.0"), Some("api"));
+/// ```
+#[must_use]
+pub fn user_agent_category(user_agent: &str) -> Option<&'static str> {
+    const BOTS: [&str; 8] = [
+        "bot",
+        "crawl",
+        "spider",
+        "slurp",
+        "monitor",
+        "headless",
+        "lighthouse",
+        "facebookexternalhit",
+    ];
+    const MOBILE: [&str; 5] = ["mobile", "android", "iphone", "ipad", "tablet"];
+
+    let user_agent = user_agent.trim();
+    if user_agent.is_empty() {
+        return None;
+    }
+    if BOTS.iter().any(|bot| contains_ignore_case(user_agent, bot)) {
+        return Some("bot");
+    }
+    let is_browser = user_agent
+        .get(..8)
+        .is_some_and(|start| start.eq_ignore_ascii_case("mozilla/"))
+        || user_agent
+            .get(..5)
+            .is_some_and(|start| start.eq_ignore_ascii_case("opera"));
+    if !is_browser {
+        return Some("api");
+    }
+    if MOBILE
+        .iter()
+        .any(|mobile| contains_ignore_case(user_agent, mobile))
+    {
+        Some("mobile")
+    } else {
+        Some("browser")
+    }
+}
+
+/// Whether `haystack` contains the lowercase ASCII `needle`, ignoring case
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .any(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
 // If remote request has no span data the propagator defaults to an unsampled context
 #[must_use]
 fn extract_context<B>(req: &Request<B>) -> opentelemetry::Context {
@@ -180,75 +1938,330 @@ pin_project! {
     pub struct ResponseFuture<F> {
         #[pin]
         pub(crate) inner: F,
+        pub(crate) layer: Arc<AxumOtelLayer>,
         pub(crate) span: Span,
         pub(crate) start: Instant,
+        pub(crate) request: RequestInfo,
+        // the incoming trace context of a request without a span
+        pub(crate) parent: Option<opentelemetry::Context>,
+        pub(crate) active: Option<ActiveRequest>,
+    }
+}
+
+/// Request data that is needed when the response is ready
+#[derive(Debug)]
+pub(crate) struct RequestInfo {
+    method: http::Method,
+    matched_path: Option<MatchedPath>,
+    /// the route from `with_route_normalizer`, if it differs from the matched path
+    normalized_route: Option<String>,
+    /// the request path, if the router did not give us a matched route
+    unmatched_path: Option<String>,
+    /// the request without the body, for `with_request_response_attributes`
+    parts: Option<http::request::Parts>,
+    /// the `Content-Length` of the request
+    body_size: Option<u64>,
+    /// the request id to return in the response
+    request_id: Option<(HeaderName, HeaderValue)>,
+    /// the request is conditional, for `with_conditional_requests`
+    conditional: bool,
+    /// the request has a [`SpanName`] extension
+    named: bool,
+}
+
+impl RequestInfo {
+    fn new<B>(req: &Request<B>, layer: &AxumOtelLayer) -> Self {
+        let matched_path = req.extensions().get::<MatchedPath>().cloned();
+        RequestInfo {
+            method: req.method().clone(),
+            unmatched_path: matched_path.is_none().then(|| req.uri().path().to_string()),
+            normalized_route: matched_path
+                .as_ref()
+                .and_then(|path| layer.normalize_route(path.as_str())),
+            matched_path,
+            parts: None,
+            body_size: content_length(req.headers()),
+            request_id: layer.request_id.as_ref().and_then(|(header, _)| {
+                let value = req.headers().get(header)?;
+                Some((header.clone(), value.clone()))
+            }),
+            conditional: layer.conditional_requests && is_conditional(req.headers()),
+            named: req.extensions().get::<SpanName>().is_some(),
+        }
+    }
+
+    /// The route as recorded in `http.route`
+    fn route(&self) -> &str {
+        match &self.normalized_route {
+            Some(route) => route,
+            None => self.matched_path.as_ref().map_or("", MatchedPath::as_str),
+        }
+    }
+
+    fn start_metrics(&self, layer: &AxumOtelLayer) -> Option<ActiveRequest> {
+        layer
+            .http_metrics
+            .as_ref()
+            .map(|metrics| metrics.start(&self.method, self.route()))
     }
 }
 
 /// The future created when the request is started
 ///
 /// Updates the tracing span with the statuscode etc
-///
-/// TODO: Also tries to propagate the context, ie set
-/// the header 'traceparent'
+/// and propagates the context by setting the headers `traceparent` and `tracestate`
 impl<Fut, ResBody, E> Future for ResponseFuture<Fut>
 where
     Fut: Future<Output = Result<Response<ResBody>, E>>,
     E: std::error::Error + 'static,
+    ResBody: http_body::Body,
 {
-    type Output = Result<Response<ResBody>, E>;
+    type Output = Result<Response<ResponseBody<ResBody>>, E>;
 
-    #[allow(unused_mut)]
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
         let _guard = this.span.enter();
-        let mut result = futures_util::ready!(this.inner.poll(cx));
-        update_span_from_response_or_error(this.span, &result);
-        // if result.is_ok() {
-        //     set_tracing_header(&this.span, result.unwrap().as_ref().headers_mut());
-        // }
+        let _context = this.parent.clone().map(opentelemetry::Context::attach);
+        let poll = std::panic::catch_unwind(AssertUnwindSafe(|| this.inner.poll(cx)));
+        let mut result = match poll {
+            Ok(poll) => futures_util::ready!(poll),
+            Err(payload) => {
+                update_span_from_panic(this.span, this.layer, this.request, payload.as_ref());
+                if let Some(metrics) = &this.layer.http_metrics {
+                    metrics.record(
+                        &this.request.method,
+                        this.request.route(),
+                        Some(500),
+                        this.start.elapsed(),
+                        (this.request.body_size, None),
+                    );
+                }
+                std::panic::resume_unwind(payload);
+            }
+        };
+        if this.layer.lifecycle_events && !this.span.is_none() {
+            this.span.add_event("response.sent", Vec::new());
+        }
+        let ok_status =
+            update_span_from_response_or_error(this.span, this.layer, this.request, &result);
+        if let Some(metrics) = &this.layer.http_metrics {
+            let response = result.as_ref().ok();
+            let response_size = response.and_then(|response| content_length(response.headers()));
+            metrics.record(
+                &this.request.method,
+                this.request.route(),
+                response.map(|response| response.status().as_u16()),
+                this.start.elapsed(),
+                (this.request.body_size, response_size),
+            );
+            this.active.take();
+        }
+        if let Ok(response) = &mut result
+            && let Some((header, value)) = &this.request.request_id
+            && !response.headers().contains_key(header)
+        {
+            response.headers_mut().insert(header.clone(), value.clone());
+        }
+        if !this.layer.omit_traceparent
+            && let Ok(response) = &mut result
+        {
+            set_tracing_header(this.span, response.headers_mut());
+        }
+        if !this.span.is_none()
+            && let Some(snapshot) = &this.layer.resource_snapshot
+        {
+            for (key, value) in snapshot() {
+                this.span.set_attribute(key, value);
+            }
+        }
+        if !this.span.is_none()
+            && let Some(attributes) = &this.layer.request_response_attributes
+            && let Some(request_parts) = &this.request.parts
+            && let Ok(response) = result
+        {
+            let (parts, body) = response.into_parts();
+            for (key, value) in attributes(request_parts, &parts) {
+                this.span.set_attribute(key, value);
+            }
+            result = Ok(Response::from_parts(parts, body));
+        }
+        if !this.span.is_none()
+            && let Some(hook) = &this.layer.on_span_end
+        {
+            let elapsed = this.start.elapsed();
+            match result {
+                Ok(response) => {
+                    let (parts, body) = response.into_parts();
+                    hook(this.span, Some(&parts), elapsed);
+                    result = Ok(Response::from_parts(parts, body));
+                }
+                Err(_) => hook(this.span, None, elapsed),
+            }
+        }
+        let result = result.map(|response| {
+            let status = response.status();
+            // hyper drops these bodies without polling them
+            let sends_body = this.request.method != http::Method::HEAD
+                && !status.is_informational()
+                && status != http::StatusCode::NO_CONTENT
+                && status != http::StatusCode::NOT_MODIFIED;
+            response.map(|body| {
+                ResponseBody::new(
+                    body,
+                    this.span.clone(),
+                    Arc::clone(this.layer),
+                    ok_status,
+                    sends_body,
+                )
+            })
+        });
         Poll::Ready(result)
     }
 }
 
-#[allow(unused)]
+/// The W3C trace context version
+const SUPPORTED_VERSION: u8 = 0;
+
 fn set_tracing_header(span: &Span, headers: &mut HeaderMap) {
+    if span.is_none() || headers.contains_key("traceparent") {
+        return;
+    }
     let ctx = span.context();
     let ctx_span = ctx.span();
     let span_context = ctx_span.span_context();
+    // unsampled contexts are returned too, so the caller sees the decision
     if span_context.is_valid() {
         let header_value = format!(
             "{:02x}-{}-{}-{:02x}",
-            0, // = SUPPORTED_VERSION,
+            SUPPORTED_VERSION,
             span_context.trace_id(),
             span_context.span_id(),
             span_context.trace_flags() & TraceFlags::SAMPLED
         );
 
-        HeaderValue::from_str(&header_value).map(|value| headers.insert("traceparent", value));
+        if let Ok(value) = HeaderValue::from_str(&header_value) {
+            headers.insert("traceparent", value);
+        }
+        // the vendor entries of the caller, passed on by the sampler
+        let trace_state = span_context.trace_state().header();
+        if !trace_state.is_empty()
+            && let Ok(value) = HeaderValue::from_str(&trace_state)
+        {
+            headers.insert("tracestate", value);
+        }
     }
 }
 
-fn update_span_from_response<B>(span: &tracing::Span, response: &http::Response<B>) {
+/// Returns whether `otel.status_code = OK` is to be recorded when the body has
+/// been sent. It is not recorded here, as OK can't be changed to ERROR when the
+/// span has been started, and the body can still fail.
+fn update_span_from_response<B>(
+    span: &tracing::Span,
+    layer: &AxumOtelLayer,
+    request: &RequestInfo,
+    response: &http::Response<B>,
+) -> bool {
     let status = response.status();
     span.record("http.response.status_code", status.as_u16());
+    let is_error = status.is_server_error()
+        || (status.is_client_error() && layer.client_error_status == ClientErrorStatus::Error);
+    if let Some(size) = content_length(response.headers()) {
+        span.record("http.response.body.size", size);
+    }
+    let trailer_error = record_trailers(span, layer, response.headers());
+    record_resend_count(span, response.extensions());
+    record_claims(span, layer, response.extensions());
+    record_operation_id(span, layer, response.extensions());
+    record_rate_limit(span, layer, response);
+    if response.extensions().get::<Fallback>().is_some()
+        || (layer.fallback_detection
+            && request.matched_path.is_none()
+            && status != http::StatusCode::NOT_FOUND
+            && status != http::StatusCode::METHOD_NOT_ALLOWED)
+    {
+        span.set_attribute("http.route.fallback", true);
+    }
+    if request.conditional {
+        span.set_attribute(
+            "http.response.not_modified",
+            status == http::StatusCode::NOT_MODIFIED,
+        );
+    }
 
-    if status.is_server_error() {
+    if status == http::StatusCode::METHOD_NOT_ALLOWED {
+        span.record("http.method_not_allowed", true);
+        // The router knows the path, but only gives a matched route to layers
+        // added with `Router::layer`. Use the path so method mismatches
+        // are grouped by path instead of in the unmatched bucket.
+        if let Some(path) = &request.unmatched_path {
+            span.record("http.route", path.as_str());
+            if !layer.omit_otel_name && !request.named {
+                rename_span(span, layer.otel_name(request.method.as_str(), path));
+            }
+        }
+    }
+    if let Some(SpanName(name)) = response.extensions().get::<SpanName>() {
+        rename_span(span, name.clone());
+    }
+
+    if is_error {
         span.record("otel.status_code", "ERROR");
+        span.record("error.source", "http_status");
         // see [http-spans.md#status](https://github.com/open-telemetry/semantic-conventions/blob/v1.25.0/docs/http/http-spans.md#status)
         // Span Status MUST be left unset if HTTP status code was in the 1xx, 2xx or 3xx ranges,
         // unless there was another error (e.g., network error receiving the response body;
         // or 3xx codes with max redirects exceeded), in which case status MUST be set to Error.
-        // } else {
-        //     span.record("otel.status_code", "OK");
+        // `with_ok_status` sets OK for those, for teams that query on it.
+        if layer.error_logs && !span.is_none() {
+            tracing::error!(
+                target: ERROR_LOG_TARGET,
+                {
+                    http.request.method = request.method.as_str(),
+                    http.route = request.route(),
+                    http.response.status_code = status.as_u16(),
+                },
+                "HTTP request failed with status {status}"
+            );
+        }
     }
+    !is_error
+        && !trailer_error
+        && if status.is_client_error() {
+            layer.client_error_status == ClientErrorStatus::Ok
+        } else {
+            layer.ok_status
+        }
 }
 
-fn update_span_from_error<E>(span: &tracing::Span, error: &E)
-where
+/// Set `otel.name` when the response is ready
+///
+/// The otel span may have been started by then (eg. by the handler getting its
+/// context), and recording the field no longer changes its name.
+fn rename_span(span: &Span, name: String) {
+    span.record("otel.name", name.as_str());
+    span.context().span().update_name(name);
+}
+
+fn update_span_from_error<E>(
+    span: &tracing::Span,
+    layer: &AxumOtelLayer,
+    request: &RequestInfo,
+    error: &E,
+) where
     E: Error,
 {
+    if layer.error_logs && !span.is_none() {
+        tracing::error!(
+            target: ERROR_LOG_TARGET,
+            {
+                http.request.method = request.method.as_str(),
+                http.route = request.route(),
+            },
+            "HTTP request failed: {error}"
+        );
+    }
     span.record("otel.status_code", "ERROR");
+    span.record("error.source", "transport");
     //span.record("http.status_code", 500);
     span.record("exception.message", error.to_string());
     error
@@ -256,18 +2269,354 @@ where
         .map(|s| span.record("exception.message", s.to_string()));
 }
 
+fn update_span_from_panic(
+    span: &tracing::Span,
+    layer: &AxumOtelLayer,
+    request: &RequestInfo,
+    payload: &(dyn Any + Send),
+) {
+    let message = panic::message(payload);
+    let location = panic::take_location();
+    if layer.error_logs && !span.is_none() {
+        tracing::error!(
+            target: ERROR_LOG_TARGET,
+            {
+                http.request.method = request.method.as_str(),
+                http.route = request.route(),
+            },
+            "HTTP request panicked: {message}"
+        );
+    }
+    span.record("otel.status_code", "ERROR");
+    span.record("http.response.status_code", 500);
+    span.record("error.source", "panic");
+    span.record("exception.message", message);
+    if layer.panic_locations
+        && let Some(location) = location
+    {
+        span.set_attribute("exception.stacktrace", format!("at {location}"));
+    }
+}
+
 fn update_span_from_response_or_error<B, E>(
     span: &tracing::Span,
+    layer: &AxumOtelLayer,
+    request: &RequestInfo,
     response: &Result<http::Response<B>, E>,
-) where
+) -> bool
+where
     E: Error,
 {
     match response {
-        Ok(response) => {
-            update_span_from_response(span, response);
-        }
+        Ok(response) => update_span_from_response(span, layer, request, response),
         Err(err) => {
-            update_span_from_error(span, err);
+            update_span_from_error(span, layer, request, err);
+            false
+        }
+    }
+}
+
+/// Record the configured trailers (or trailers-only headers) on the span,
+/// returns whether they marked the span as an error
+pub(crate) fn record_trailers(
+    span: &tracing::Span,
+    layer: &AxumOtelLayer,
+    trailers: &HeaderMap,
+) -> bool {
+    let mut is_error = false;
+    for name in &layer.recorded_trailers {
+        let Some(value) = trailers.get(name).and_then(|v| v.to_str().ok()) else {
+            continue;
+        };
+        span.set_attribute(
+            format!("http.response.trailer.{}", name.as_str().replace('-', "_")),
+            value.to_string(),
+        );
+        if name == "grpc-status"
+            && let Ok(code) = value.parse::<i64>()
+        {
+            span.set_attribute("rpc.grpc.status_code", code);
+            // UNKNOWN, DEADLINE_EXCEEDED, UNIMPLEMENTED, INTERNAL, UNAVAILABLE, DATA_LOSS
+            // see [grpc.md](https://github.com/open-telemetry/semantic-conventions/blob/v1.25.0/docs/rpc/grpc.md#grpc-status)
+            if matches!(code, 2 | 4 | 12 | 13 | 14 | 15) {
+                span.record("otel.status_code", "ERROR");
+                is_error = true;
+            }
+        }
+    }
+    is_error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Headers with the given `(name, value)` lines, in order
+    fn header_lines(lines: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in lines {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn parse_request_start_guesses_the_unit() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_millis(1_609_459_200_123);
+        for value in [
+            "t=1609459200.123",
+            "1609459200.123",
+            " t=1609459200.123 ",
+            "t=1609459200123",
+            "1609459200123",
+            "t=1609459200123000",
+            "1609459200123000",
+        ] {
+            let parsed = parse_request_start(value).unwrap();
+            let error = parsed
+                .duration_since(start)
+                .unwrap_or_else(|err| err.duration());
+            assert!(error < Duration::from_micros(1), "{value}: {parsed:?}");
+        }
+    }
+
+    #[test]
+    fn parse_request_start_rejects_malformed_values() {
+        for value in [
+            "",
+            "t=",
+            "t",
+            "abc",
+            "t=abc",
+            "1609459200,123",
+            "1.2.3",
+            "-1",
+            "t=-1",
+            "NaN",
+            "inf",
+            "1e400",
+            "T=1609459200",
+        ] {
+            assert_eq!(parse_request_start(value), None, "{value}");
+            assert_eq!(queue_time(value, SystemTime::now()), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn queue_time_is_recorded_for_past_starts_only() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_609_459_200);
+        for (value, expected) in [
+            ("t=1609459199.5", Some(Duration::from_millis(500))),
+            ("1609459199500", Some(Duration::from_millis(500))),
+            ("1609459199500000", Some(Duration::from_millis(500))),
+            ("t=1609459200", Some(Duration::ZERO)),
+            ("t=1609459200.5", None),
+            ("1609459260000", None),
+        ] {
+            assert_eq!(queue_time(value, now), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn primary_language_has_the_highest_weight() {
+        for (header, expected) in [
+            ("da-DK,da;q=0.9,en;q=0.8", Some("da")),
+            ("en;q=0.5, DA-dk;q=0.8", Some("da")),
+            ("fr-CH, fr;q=0.9, en;q=0.8, de;q=0.7", Some("fr")),
+            ("en;q=0.8,da;q=0.8", Some("en")),
+            ("en;Q=0.1, de ; q = 0.2", Some("de")),
+            ("sv", Some("sv")),
+            ("", None),
+        ] {
+            assert_eq!(primary_language(header).as_deref(), expected, "{header}");
+        }
+    }
+
+    #[test]
+    fn primary_language_skips_wildcard_and_unacceptable_languages() {
+        for (header, expected) in [
+            ("*", None),
+            ("*, en;q=0.5", Some("en")),
+            ("en;q=0", None),
+            ("en;q=0.000, de;q=0.1", Some("de")),
+            ("da;q=0, en", Some("en")),
+        ] {
+            assert_eq!(primary_language(header).as_deref(), expected, "{header}");
+        }
+    }
+
+    #[test]
+    fn primary_language_skips_malformed_weights() {
+        for (header, expected) in [
+            ("en;q=abc, de;q=0.1", Some("de")),
+            ("en;q=, de;q=0.1", Some("de")),
+            ("en;q=2, de;q=0.1", Some("de")),
+            ("en;q=-1, de;q=0.1", Some("de")),
+            ("en;q=NaN, de;q=0.1", Some("de")),
+            ("en;q=abc", None),
+            (";q=0.5, , de", Some("de")),
+        ] {
+            assert_eq!(primary_language(header).as_deref(), expected, "{header}");
+        }
+    }
+
+    #[test]
+    fn new_request_id_is_a_uuid_v4() {
+        for _ in 0..100 {
+            let id = new_request_id();
+            let groups: Vec<&str> = id.split('-').collect();
+            assert_eq!(
+                groups.iter().map(|group| group.len()).collect::<Vec<_>>(),
+                [8, 4, 4, 4, 12],
+                "{id}"
+            );
+            assert!(
+                groups
+                    .iter()
+                    .flat_map(|group| group.chars())
+                    .all(|c| matches!(c, '0'..='9' | 'a'..='f')),
+                "{id}"
+            );
+            // the version nibble, and the variant bits `10`
+            assert!(groups[2].starts_with('4'), "{id}");
+            assert!(groups[3].starts_with(['8', '9', 'a', 'b']), "{id}");
+        }
+    }
+
+    #[test]
+    fn new_request_ids_differ() {
+        let ids: HashSet<String> = (0..100).map(|_| new_request_id()).collect();
+        assert_eq!(ids.len(), 100);
+    }
+
+    #[test]
+    fn truncate_keeps_values_that_fit() {
+        for value in ["", "abc", "äbc"] {
+            let mut cut = value.to_string();
+            assert!(!truncate(&mut cut, value.len()), "{value}");
+            assert_eq!(cut, value);
+            assert_eq!(truncate_str(value, value.len()), Cow::Borrowed(value));
+        }
+    }
+
+    #[test]
+    fn truncate_cuts_at_a_char_boundary() {
+        // "ä" is 2 bytes, the marker 3
+        let value = "aäää";
+        assert_eq!(truncate_str(value, 6), "aä…");
+        assert_eq!(truncate_str(value, 5), "a…");
+        assert_eq!(truncate_str(value, 4), "a…");
+        let mut cut = value.to_string();
+        assert!(truncate(&mut cut, 5));
+        assert_eq!(cut, "a…");
+    }
+
+    #[test]
+    fn truncate_leaves_out_the_marker_without_room() {
+        for (max_len, expected) in [(0, ""), (1, "a"), (2, "ab"), (3, "…"), (4, "a…")] {
+            let cut = truncate_str("abcdef", max_len);
+            assert_eq!(cut, expected, "{max_len}");
+            assert!(cut.len() <= max_len);
+        }
+        assert_eq!(truncate_str("äbc", 1), "");
+    }
+
+    #[test]
+    fn forwarded_address_strips_the_port() {
+        for (value, expected) in [
+            ("192.0.2.1:4711", "192.0.2.1"),
+            ("[2001:db8::1]:4711", "2001:db8::1"),
+            ("2001:db8::1", "2001:db8::1"),
+            ("192.0.2.1", "192.0.2.1"),
+        ] {
+            let headers = header_lines(&[("x-forwarded-for", value)]);
+            assert_eq!(
+                forwarded_address(&headers, ForwardedFor::Rightmost).as_deref(),
+                Some(expected),
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn forwarded_address_unquotes_forwarded_values() {
+        for (value, expected) in [
+            (r#"for="[2001:db8::1]:4711""#, "2001:db8::1"),
+            (r#"For="192.0.2.1:4711";proto=https"#, "192.0.2.1"),
+            ("proto=https; for=192.0.2.1", "192.0.2.1"),
+        ] {
+            let headers = header_lines(&[("forwarded", value)]);
+            assert_eq!(
+                forwarded_address(&headers, ForwardedFor::Rightmost).as_deref(),
+                Some(expected),
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn forwarded_address_picks_the_entry_across_header_lines() {
+        let headers = header_lines(&[
+            ("x-forwarded-for", "192.0.2.1, 192.0.2.2"),
+            ("x-forwarded-for", "192.0.2.3"),
+        ]);
+        assert_eq!(
+            forwarded_address(&headers, ForwardedFor::Leftmost).as_deref(),
+            Some("192.0.2.1")
+        );
+        assert_eq!(
+            forwarded_address(&headers, ForwardedFor::Rightmost).as_deref(),
+            Some("192.0.2.3")
+        );
+        let headers = header_lines(&[
+            ("forwarded", "for=192.0.2.1"),
+            ("forwarded", "for=192.0.2.2, for=192.0.2.3"),
+        ]);
+        assert_eq!(
+            forwarded_address(&headers, ForwardedFor::Leftmost).as_deref(),
+            Some("192.0.2.1")
+        );
+        assert_eq!(
+            forwarded_address(&headers, ForwardedFor::Rightmost).as_deref(),
+            Some("192.0.2.3")
+        );
+    }
+
+    #[test]
+    fn forwarded_address_is_not_taken_from_a_header_sent_by_the_client() {
+        // the proxy appends to X-Forwarded-For, the client sent Forwarded
+        let headers = header_lines(&[
+            ("forwarded", "for=198.51.100.7"),
+            ("x-forwarded-for", "192.0.2.1"),
+        ]);
+        assert_eq!(forwarded_address(&headers, ForwardedFor::Rightmost), None);
+        // a proxy that sets both
+        let headers = header_lines(&[
+            ("forwarded", "for=192.0.2.1"),
+            ("x-forwarded-for", "192.0.2.1"),
+        ]);
+        assert_eq!(
+            forwarded_address(&headers, ForwardedFor::Rightmost).as_deref(),
+            Some("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_address_skips_obfuscated_identifiers() {
+        for value in [
+            "for=unknown",
+            "for=_hidden",
+            r#"for="_hidden:4711""#,
+            "for=",
+        ] {
+            let headers = header_lines(&[("forwarded", value)]);
+            assert_eq!(
+                forwarded_address(&headers, ForwardedFor::Rightmost),
+                None,
+                "{value}"
+            );
         }
+        let headers = header_lines(&[("x-forwarded-for", "192.0.2.1, unknown")]);
+        assert_eq!(forwarded_address(&headers, ForwardedFor::Rightmost), None);
     }
 }
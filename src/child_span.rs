@@ -0,0 +1,39 @@
+//! Child spans of the request span
+
+use std::future::Future;
+use tracing::{Instrument, field::Empty, info_span, instrument::Instrumented};
+
+/// Run `future` in a child span of the current span, with `name` as `otel.name`
+///
+/// In a handler the current span is the request span, so the child span is in
+/// the trace of the request in Honeycomb. The span has the fields
+/// `otel.status_code` and `exception.message`, to record a failure with:
+/// ```
+/// use axum_otlp_honeycomb::instrument_child;
+///
+/// async fn load_user(id: u64) -> Result<String, std::io::Error> {
+///     Ok(format!("user {id}"))
+/// }
+///
+/// async fn handler() -> String {
+///     instrument_child("load user", async {
+///         load_user(42).await.unwrap_or_else(|err| {
+///             let span = tracing::Span::current();
+///             span.record("otel.status_code", "ERROR");
+///             span.record("exception.message", err.to_string());
+///             String::new()
+///         })
+///     })
+///     .await
+/// }
+/// ```
+/// The tracing span is named `child`, only `otel.name` is `name`.
+pub fn instrument_child<F: Future>(name: &str, future: F) -> Instrumented<F> {
+    let span = info_span!(
+        "child",
+        otel.name = name,
+        otel.status_code = Empty,
+        exception.message = Empty,
+    );
+    future.instrument(span)
+}
@@ -0,0 +1,588 @@
+//! Configuration of the exporters without environment variables
+
+use std::time::Duration;
+
+/// The transport used to send the data
+///
+/// Honeycomb accepts both on port 443. An OpenTelemetry Collector listens for
+/// OTLP/HTTP on port 4318 and for OTLP/gRPC on port 4317 by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// Protobuf over HTTP, eg. to `https://api.eu1.honeycomb.io/` or `http://localhost:4318`
+    #[default]
+    HttpProtobuf,
+    /// gRPC, eg. to `http://localhost:4317`
+    Grpc,
+}
+
+/// The compression of the exported data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Send the data uncompressed
+    #[default]
+    None,
+    /// Compress the data with gzip, which Honeycomb accepts. This costs some
+    /// CPU but sends much less data.
+    Gzip,
+}
+
+/// Where the spans are exported, see `OtlpConfigBuilder::traces_exporter()`
+#[cfg(feature = "traces")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TracesExporter {
+    /// Export to Honeycomb or a collector with OTLP, `otlp`
+    #[default]
+    Otlp,
+    /// Print the spans to stdout, for development, `console`
+    Console,
+    /// Don't export the spans, `none`. The trace context is still propagated.
+    None,
+}
+
+#[cfg(feature = "traces")]
+impl TracesExporter {
+    /// The exporter given in `OTEL_TRACES_EXPORTER`, `None` when it is not set
+    /// or not supported
+    fn from_env() -> Option<Self> {
+        let value = std::env::var("OTEL_TRACES_EXPORTER").ok()?;
+        match value.trim().to_ascii_lowercase().as_str() {
+            "otlp" => Some(TracesExporter::Otlp),
+            "console" => Some(TracesExporter::Console),
+            "none" => Some(TracesExporter::None),
+            _ => None,
+        }
+    }
+}
+
+/// How a span is sampled, see `OtlpConfigBuilder::root_sampler()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamplerChoice {
+    /// Sample all spans
+    AlwaysOn,
+    /// Sample no spans
+    AlwaysOff,
+    /// Sample the configured sample rate of the traces
+    Ratio,
+    /// Sample the span if the parent span is sampled. For root spans, which
+    /// have no parent, this is the same as `Ratio`.
+    FollowParent,
+}
+
+/// A propagator of the context in the HTTP headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagator {
+    /// The W3C `traceparent` and `tracestate` headers
+    TraceContext,
+    /// The W3C `baggage` header
+    Baggage,
+}
+
+/// The Honeycomb region of the team, which decides the endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HoneycombRegion {
+    /// `https://api.honeycomb.io/`
+    Us,
+    /// `https://api.eu1.honeycomb.io/`
+    Eu,
+}
+
+impl HoneycombRegion {
+    /// The base endpoint of the region
+    #[must_use]
+    pub fn endpoint(self) -> &'static str {
+        match self {
+            HoneycombRegion::Us => "https://api.honeycomb.io/",
+            HoneycombRegion::Eu => "https://api.eu1.honeycomb.io/",
+        }
+    }
+}
+
+/// Configuration for `init_otlp_layer_with_config()` and `init_otlp_log_layer_with_config()`
+///
+/// ```
+/// # use axum_otlp_honeycomb::{HoneycombRegion, OtlpConfig};
+/// let config = OtlpConfig::builder()
+///     .api_key("<the Honeycomb Ingress API key>")
+///     .region(HoneycombRegion::Eu)
+///     .service_name("my-service")
+///     .sample_rate(0.01)
+///     .build();
+/// ```
+///
+/// Values that are not set are read from the usual environment variables
+/// `OTEL_EXPORTER_OTLP_HEADERS`, `OTEL_EXPORTER_OTLP_ENDPOINT` and `OTEL_SERVICE_NAME`.
+#[derive(Debug, Clone)]
+pub struct OtlpConfig {
+    pub(crate) api_key: Option<String>,
+    endpoint: Option<String>,
+    region: Option<HoneycombRegion>,
+    pub(crate) service_name: Option<String>,
+    pub(crate) resource_attributes: Vec<(String, String)>,
+    pub(crate) deployment_color: Option<String>,
+    pub(crate) canary: Option<bool>,
+    sample_rate: Option<f64>,
+    /// the sample rate given to `try_init_otlp_layer()`
+    #[cfg(feature = "traces")]
+    default_sample_rate: f64,
+    pub(crate) latency_threshold: Option<Duration>,
+    pub(crate) keep_error_traces: bool,
+    pub(crate) root_sampler: SamplerChoice,
+    pub(crate) remote_parent_sampler: SamplerChoice,
+    pub(crate) local_parent_sampler: SamplerChoice,
+    pub(crate) protocol: Protocol,
+    #[cfg(feature = "traces")]
+    traces_exporter: Option<TracesExporter>,
+    pub(crate) compression: Compression,
+    pub(crate) timeout: Option<Duration>,
+    /// PEM encoded CA certificates trusted in addition to the system roots
+    pub(crate) root_certificates: Vec<Vec<u8>>,
+    pub(crate) client_identity: Option<ClientIdentity>,
+    pub(crate) max_queue_size: Option<usize>,
+    pub(crate) scheduled_delay: Option<Duration>,
+    pub(crate) max_export_batch_size: Option<usize>,
+    max_concurrent_exports: Option<usize>,
+    pub(crate) propagators: Vec<Propagator>,
+    /// install the propagators when the tracing layer is created
+    pub(crate) install_propagators: bool,
+    pub(crate) scope_name: Option<String>,
+    pub(crate) scope_version: Option<String>,
+}
+
+/// Client certificate and key for mutual TLS, the key is left out of `Debug`
+#[derive(Clone)]
+pub(crate) struct ClientIdentity {
+    pub(crate) cert: Vec<u8>,
+    #[cfg_attr(
+        not(any(feature = "traces", feature = "logs", feature = "metrics")),
+        allow(dead_code)
+    )]
+    pub(crate) key: Vec<u8>,
+}
+
+impl std::fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientIdentity")
+            .field("cert", &String::from_utf8_lossy(&self.cert))
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        OtlpConfig {
+            api_key: None,
+            endpoint: None,
+            region: None,
+            service_name: None,
+            resource_attributes: Vec::new(),
+            deployment_color: None,
+            canary: None,
+            sample_rate: None,
+            #[cfg(feature = "traces")]
+            default_sample_rate: 1.0,
+            latency_threshold: None,
+            keep_error_traces: false,
+            root_sampler: SamplerChoice::Ratio,
+            remote_parent_sampler: SamplerChoice::FollowParent,
+            local_parent_sampler: SamplerChoice::FollowParent,
+            protocol: Protocol::default(),
+            #[cfg(feature = "traces")]
+            traces_exporter: None,
+            compression: Compression::default(),
+            timeout: None,
+            root_certificates: Vec::new(),
+            client_identity: None,
+            max_queue_size: None,
+            scheduled_delay: None,
+            max_export_batch_size: None,
+            max_concurrent_exports: None,
+            propagators: vec![Propagator::TraceContext, Propagator::Baggage],
+            install_propagators: true,
+            scope_name: None,
+            scope_version: None,
+        }
+    }
+}
+
+impl OtlpConfig {
+    /// Start building a configuration
+    #[must_use]
+    pub fn builder() -> OtlpConfigBuilder {
+        OtlpConfigBuilder::default()
+    }
+
+    /// The fraction of traces to keep, clamped to 0.0..=1.0
+    ///
+    /// The value set in the builder wins over `OTEL_TRACES_SAMPLER_ARG`, which
+    /// wins over the argument to `try_init_otlp_layer()`. Values that are not
+    /// numbers are skipped.
+    #[cfg(feature = "traces")]
+    pub(crate) fn sample_rate(&self) -> f64 {
+        let from_env = || {
+            std::env::var("OTEL_TRACES_SAMPLER_ARG")
+                .ok()
+                .and_then(|rate| rate.trim().parse::<f64>().ok())
+        };
+        self.sample_rate
+            .filter(|rate| !rate.is_nan())
+            .or_else(|| from_env().filter(|rate| !rate.is_nan()))
+            .or(Some(self.default_sample_rate).filter(|rate| !rate.is_nan()))
+            .unwrap_or(1.0)
+            .clamp(0.0, 1.0)
+    }
+
+    /// The number of span exports that can run at once, as set or from
+    /// `OTEL_BSP_MAX_CONCURRENT_EXPORTS`, at least 1
+    #[cfg(feature = "traces")]
+    pub(crate) fn max_concurrent_exports(&self) -> usize {
+        self.max_concurrent_exports
+            .or_else(|| {
+                std::env::var("OTEL_BSP_MAX_CONCURRENT_EXPORTS")
+                    .ok()
+                    .and_then(|exports| exports.trim().parse().ok())
+            })
+            .unwrap_or(1)
+            .max(1)
+    }
+
+    /// The exporter that was set, or else the one in `OTEL_TRACES_EXPORTER`
+    #[cfg(feature = "traces")]
+    pub(crate) fn traces_exporter(&self) -> TracesExporter {
+        self.traces_exporter
+            .or_else(TracesExporter::from_env)
+            .unwrap_or_default()
+    }
+
+    /// The endpoint that was set, or else the endpoint of the region
+    #[cfg(any(feature = "traces", feature = "logs", feature = "metrics"))]
+    pub(crate) fn endpoint(&self) -> Option<String> {
+        self.endpoint
+            .clone()
+            .or_else(|| self.region.map(|region| region.endpoint().to_string()))
+    }
+
+    /// The endpoint for a signal, eg. `https://api.eu1.honeycomb.io/v1/traces`
+    ///
+    /// gRPC uses the endpoint as it is.
+    #[cfg(any(feature = "traces", feature = "logs", feature = "metrics"))]
+    pub(crate) fn signal_endpoint(&self, path: &str) -> Option<String> {
+        self.endpoint().map(|endpoint| match self.protocol {
+            Protocol::HttpProtobuf => format!("{}{path}", endpoint.trim_end_matches('/')),
+            Protocol::Grpc => endpoint,
+        })
+    }
+}
+
+/// Builder for [`OtlpConfig`]
+#[derive(Debug, Clone, Default)]
+pub struct OtlpConfigBuilder {
+    config: OtlpConfig,
+}
+
+impl OtlpConfigBuilder {
+    /// The Honeycomb Ingress API key, sent in the `x-honeycomb-team` header
+    ///
+    /// An `x-honeycomb-team` in `OTEL_EXPORTER_OTLP_HEADERS` wins, the other
+    /// headers there (eg. `x-honeycomb-dataset`) are sent along.
+    #[must_use]
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.config.api_key = Some(api_key.into());
+        self
+    }
+
+    /// The base endpoint for Honeycomb, eg. `https://api.eu1.honeycomb.io/`
+    ///
+    /// For HTTP `/v1/traces` and `/v1/logs` are appended for the signals.
+    #[must_use]
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.config.endpoint = Some(endpoint.into());
+        self
+    }
+
+    /// Send to the Honeycomb endpoint of `region`
+    ///
+    /// An endpoint set with `endpoint()` is used instead.
+    #[must_use]
+    pub fn region(mut self, region: HoneycombRegion) -> Self {
+        self.config.region = Some(region);
+        self
+    }
+
+    /// The service name, which is the dataset in Honeycomb
+    #[must_use]
+    pub fn service_name(mut self, service_name: impl Into<String>) -> Self {
+        self.config.service_name = Some(service_name.into());
+        self
+    }
+
+    /// Add attributes to the resource of the spans, logs and metrics, eg.
+    /// `[("service.version", git_sha), ("deployment.environment", "prod")]`
+    ///
+    /// They are added to the default resource attributes and those in
+    /// `OTEL_RESOURCE_ATTRIBUTES`, and win over them.
+    #[must_use]
+    pub fn resource_attributes<K, V>(mut self, attributes: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.config.resource_attributes.extend(
+            attributes
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        self
+    }
+
+    /// Add the `deployment.color` resource attribute, eg. `blue` or `green`
+    ///
+    /// For values injected per pod, `OTEL_RESOURCE_ATTRIBUTES=deployment.color=blue`
+    /// works as well.
+    #[must_use]
+    pub fn deployment_color(mut self, color: impl Into<String>) -> Self {
+        self.config.deployment_color = Some(color.into());
+        self
+    }
+
+    /// Add the `deployment.canary` resource attribute, to compare the canary
+    /// with the stable version in Honeycomb
+    #[must_use]
+    pub fn canary(mut self, canary: bool) -> Self {
+        self.config.canary = Some(canary);
+        self
+    }
+
+    /// The fraction of traces that should be sent to Honeycomb. 1.0 is all traces.
+    ///
+    /// When it is not set, `OTEL_TRACES_SAMPLER_ARG` is used if it is a number,
+    /// otherwise all traces are sent. The rate is clamped to 0.0..=1.0.
+    #[must_use]
+    pub fn sample_rate(mut self, sample_rate: f64) -> Self {
+        self.config.sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// The sample rate to use when neither `sample_rate()` nor the
+    /// environment sets it
+    #[cfg(feature = "traces")]
+    #[must_use]
+    pub(crate) fn default_sample_rate(mut self, sample_rate: f64) -> Self {
+        self.config.default_sample_rate = sample_rate;
+        self
+    }
+
+    /// Only export the spans that took at least `threshold`,
+    /// see `init_otlp_layer_with_latency_threshold()`.
+    #[must_use]
+    pub fn latency_threshold(mut self, threshold: Duration) -> Self {
+        self.config.latency_threshold = Some(threshold);
+        self
+    }
+
+    /// Keep all traces where the request failed, and `sample_rate()` of the others
+    ///
+    /// All traces are recorded and the spans of a trace are held in memory until
+    /// its root span ends, at which point the trace is dropped unless the root span
+    /// has the status `Error` (eg. a 5xx response) or is within the sample rate,
+    /// see [`ErrorTraceProcessor`](crate::ErrorTraceProcessor). This costs more CPU
+    /// and memory than only recording the sampled traces, and the sent volume grows
+    /// with the error rate: at a 1% sample rate and 5% errors about 6% of the
+    /// traces are sent.
+    ///
+    /// The rate only decides for the traces that start here: a trace coming in
+    /// with a `traceparent` keeps the decision of the caller, unless the
+    /// `remote_parent_sampler()` is [`SamplerChoice::Ratio`].
+    ///
+    /// As every trace is sampled when it starts, the `traceparent` sent to other
+    /// services has the sampled flag `01`, also for the traces this service drops
+    /// later. Services following the parent's decision then send spans whose
+    /// parent never reaches Honeycomb, so keep the services called by this one
+    /// on their own sample rate, eg. with `remote_parent_sampler(SamplerChoice::Ratio)`.
+    #[must_use]
+    pub fn keep_error_traces(mut self) -> Self {
+        self.config.keep_error_traces = true;
+        self
+    }
+
+    /// How spans without a parent are sampled, the default is [`SamplerChoice::Ratio`]
+    ///
+    /// Eg. `SamplerChoice::AlwaysOn` at the edge keeps all the traces that start
+    /// in this service, while the parent still decides for traces that come in
+    /// with a `traceparent`.
+    #[must_use]
+    pub fn root_sampler(mut self, choice: SamplerChoice) -> Self {
+        self.config.root_sampler = choice;
+        self
+    }
+
+    /// How spans with a parent from another service (the `traceparent` header) are
+    /// sampled, the default is [`SamplerChoice::FollowParent`]
+    #[must_use]
+    pub fn remote_parent_sampler(mut self, choice: SamplerChoice) -> Self {
+        self.config.remote_parent_sampler = choice;
+        self
+    }
+
+    /// How spans with a parent in this service are sampled, the default is
+    /// [`SamplerChoice::FollowParent`]
+    #[must_use]
+    pub fn local_parent_sampler(mut self, choice: SamplerChoice) -> Self {
+        self.config.local_parent_sampler = choice;
+        self
+    }
+
+    /// Where to export the spans, the default is `OTEL_TRACES_EXPORTER` or
+    /// else [`TracesExporter::Otlp`]
+    ///
+    /// `OTEL_TRACES_EXPORTER` can be `otlp`, `console` or `none`, other values
+    /// are ignored. The endpoint and service name are only required for OTLP.
+    #[cfg(feature = "traces")]
+    #[must_use]
+    pub fn traces_exporter(mut self, exporter: TracesExporter) -> Self {
+        self.config.traces_exporter = Some(exporter);
+        self
+    }
+
+    /// The transport to use, the default is [`Protocol::HttpProtobuf`]
+    #[must_use]
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.config.protocol = protocol;
+        self
+    }
+
+    /// The compression of the spans, logs and metrics, the default is
+    /// [`Compression::None`]
+    ///
+    /// With HTTP `Compression::Gzip` sends `Content-Encoding: gzip`, with gRPC
+    /// it uses the gzip message encoding.
+    #[must_use]
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.config.compression = compression;
+        self
+    }
+
+    /// The time an export may take before it is abandoned, the default is 10 seconds
+    /// or `OTEL_EXPORTER_OTLP_TIMEOUT`
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Trust the PEM encoded CA certificate(s) in `pem` in addition to the
+    /// system roots, eg. for a proxy terminating TLS with an internal CA
+    ///
+    /// Can be called more than once. Invalid PEM makes the layers fail to
+    /// initialize with `InitError::Tls`, for both protocols.
+    #[must_use]
+    pub fn root_certificate(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.config.root_certificates.push(pem.into());
+        self
+    }
+
+    /// Authenticate to the collector with the PEM encoded client certificate
+    /// (chain) `cert` and private key `key`, for mutual TLS
+    #[must_use]
+    pub fn client_identity(mut self, cert: impl Into<Vec<u8>>, key: impl Into<Vec<u8>>) -> Self {
+        self.config.client_identity = Some(ClientIdentity {
+            cert: cert.into(),
+            key: key.into(),
+        });
+        self
+    }
+
+    /// The number of spans (and logs) that can wait to be exported, the
+    /// default is 2048 or `OTEL_BSP_MAX_QUEUE_SIZE` (`OTEL_BLRP_MAX_QUEUE_SIZE`)
+    ///
+    /// When the queue is full, because Honeycomb is slow or traffic bursts,
+    /// new spans and logs are dropped. The SDK warns about it on the first drop
+    /// and gives the number of dropped items at shutdown. A larger queue uses
+    /// more memory.
+    #[must_use]
+    pub fn max_queue_size(mut self, size: usize) -> Self {
+        self.config.max_queue_size = Some(size);
+        self
+    }
+
+    /// The time between exports of the queued spans and logs, the default is
+    /// 5 seconds for spans and 1 second for logs, or `OTEL_BSP_SCHEDULE_DELAY`
+    /// (`OTEL_BLRP_SCHEDULE_DELAY`)
+    #[must_use]
+    pub fn scheduled_delay(mut self, delay: Duration) -> Self {
+        self.config.scheduled_delay = Some(delay);
+        self
+    }
+
+    /// The most spans (and logs) sent in one export, the default is 512 or
+    /// `OTEL_BSP_MAX_EXPORT_BATCH_SIZE` (`OTEL_BLRP_MAX_EXPORT_BATCH_SIZE`)
+    ///
+    /// An export starts as soon as a full batch is queued. The size is
+    /// capped at the queue size.
+    #[must_use]
+    pub fn max_export_batch_size(mut self, size: usize) -> Self {
+        self.config.max_export_batch_size = Some(size);
+        self
+    }
+
+    /// The number of span batches that can be exported to Honeycomb at once,
+    /// the default is 1 or `OTEL_BSP_MAX_CONCURRENT_EXPORTS`
+    ///
+    /// For services whose spans pile up while one batch is exported. The spans
+    /// are spread by trace id over this many batch processors, each with its own
+    /// queue, export thread and connection. So the memory use of the queues and
+    /// the number of connections to Honeycomb grow with it, and the batches can
+    /// arrive in any order (the spans of a trace stay in one queue).
+    #[must_use]
+    pub fn max_concurrent_exports(mut self, exports: usize) -> Self {
+        self.config.max_concurrent_exports = Some(exports);
+        self
+    }
+
+    /// The propagators installed as the global text map propagator, the default
+    /// is [`Propagator::TraceContext`] and [`Propagator::Baggage`]
+    ///
+    /// The request span gets the extracted context, so the baggage is also sent
+    /// on the outgoing calls made in the handler. Use `[Propagator::TraceContext]`
+    /// to not pass baggage on.
+    #[must_use]
+    pub fn propagators(mut self, propagators: impl IntoIterator<Item = Propagator>) -> Self {
+        self.config.propagators = propagators.into_iter().collect();
+        self
+    }
+
+    /// Don't install the propagators as the global text map propagator when
+    /// the tracing layer is created
+    ///
+    /// For applications that set up their own propagation, eg. B3 for a legacy
+    /// service. Call `install_propagators()` to install the configured ones
+    /// deliberately.
+    #[must_use]
+    pub fn skip_global_propagator(mut self) -> Self {
+        self.config.install_propagators = false;
+        self
+    }
+
+    /// The instrumentation scope name of the spans and logs (`otel.library.name` in Honeycomb)
+    ///
+    /// The defaults are `axum-otlp-honeycomb` for spans and `axum_otel_honeycomb` for logs.
+    #[must_use]
+    pub fn scope_name(mut self, name: impl Into<String>) -> Self {
+        self.config.scope_name = Some(name.into());
+        self
+    }
+
+    /// The instrumentation scope version of the spans and logs
+    ///
+    /// The default is no version for spans and the version of this crate for logs.
+    #[must_use]
+    pub fn scope_version(mut self, version: impl Into<String>) -> Self {
+        self.config.scope_version = Some(version.into());
+        self
+    }
+
+    /// Finish the configuration
+    #[must_use]
+    pub fn build(self) -> OtlpConfig {
+        self.config
+    }
+}
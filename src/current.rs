@@ -0,0 +1,45 @@
+//! Ids of the current span
+
+use opentelemetry::trace::{SpanContext, TraceContextExt};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// The trace id of the current span as 32 hex characters, `None` outside a
+/// (valid) span
+///
+/// In a handler this is the trace of the request, to return in an error body
+/// or to add to an access log, so support can open the trace in Honeycomb:
+/// ```
+/// use axum::http::StatusCode;
+/// use axum_otlp_honeycomb::current_trace_id;
+///
+/// async fn handler() -> (StatusCode, String) {
+///     let trace_id = current_trace_id().unwrap_or_default();
+///     (StatusCode::INTERNAL_SERVER_ERROR, format!("Something failed, trace {trace_id}"))
+/// }
+///
+/// // no span here
+/// assert_eq!(current_trace_id(), None);
+/// ```
+#[must_use]
+pub fn current_trace_id() -> Option<String> {
+    current_span_context().map(|context| context.trace_id().to_string())
+}
+
+/// The span id of the current span as 16 hex characters, `None` outside a
+/// (valid) span
+#[must_use]
+pub fn current_span_id() -> Option<String> {
+    current_span_context().map(|context| context.span_id().to_string())
+}
+
+/// The span context of the current tracing span, or else of the current
+/// `OpenTelemetry` context for code that uses the `OpenTelemetry` API directly
+fn current_span_context() -> Option<SpanContext> {
+    [
+        tracing::Span::current().context(),
+        opentelemetry::Context::current(),
+    ]
+    .into_iter()
+    .map(|context| context.span().span_context().clone())
+    .find(SpanContext::is_valid)
+}
@@ -0,0 +1,74 @@
+//! Attributes that can change while the process is running
+//!
+//! `OpenTelemetry` resources are fixed when the providers are created, so
+//! values like a rotating config version can't be resource attributes.
+//! A [`DynamicAttributes`] handle is shared between the application and the
+//! layers, and its current values are added to every request span and log record.
+
+#[cfg(feature = "logs")]
+use opentelemetry::logs::AnyValue;
+use opentelemetry::{Key, Value};
+use std::sync::{Arc, RwLock};
+
+/// Shared handle to a small set of attributes that are added to every
+/// request span and log record when they are emitted.
+///
+/// ```
+/// # use axum_otlp_honeycomb::{opentelemetry_tracing_layer, DynamicAttributes};
+/// let attributes = DynamicAttributes::new();
+/// attributes.set("config.version", 17);
+/// let layer = opentelemetry_tracing_layer().with_dynamic_attributes(attributes.clone());
+/// // later, when the config is reloaded:
+/// attributes.set("config.version", 18);
+/// ```
+///
+/// Each emit takes a read lock and clones the attributes into the span or
+/// record, so keep the set small.
+#[derive(Clone, Debug, Default)]
+pub struct DynamicAttributes {
+    attributes: Arc<RwLock<Vec<(Key, Value)>>>,
+}
+
+impl DynamicAttributes {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set (or replace) an attribute
+    pub fn set(&self, key: impl Into<Key>, value: impl Into<Value>) {
+        let (key, value) = (key.into(), value.into());
+        let mut attributes = self.attributes.write().unwrap_or_else(|e| e.into_inner());
+        match attributes.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => attributes.push((key, value)),
+        }
+    }
+
+    /// Remove an attribute
+    pub fn remove(&self, key: &str) {
+        self.attributes
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|(k, _)| k.as_str() != key);
+    }
+
+    /// Call `f` for each of the current attributes
+    pub(crate) fn for_each(&self, mut f: impl FnMut(&Key, &Value)) {
+        let attributes = self.attributes.read().unwrap_or_else(|e| e.into_inner());
+        for (key, value) in attributes.iter() {
+            f(key, value);
+        }
+    }
+}
+
+/// Convert an attribute value to a log record value
+#[cfg(feature = "logs")]
+pub(crate) fn any_value(value: &Value) -> AnyValue {
+    match value {
+        Value::Bool(b) => AnyValue::from(*b),
+        Value::I64(i) => AnyValue::from(*i),
+        Value::F64(f) => AnyValue::from(*f),
+        other => AnyValue::from(other.to_string()),
+    }
+}
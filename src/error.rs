@@ -0,0 +1,50 @@
+//! Errors when setting up the exporters
+
+use opentelemetry_otlp::ExporterBuildError;
+use std::fmt;
+
+/// Error returned when a layer can't be created
+#[derive(Debug)]
+pub enum InitError {
+    /// A required environment variable is not set
+    MissingEnv(&'static str),
+    /// The OTLP exporter could not be built
+    Exporter(ExporterBuildError),
+    /// A root certificate or the client identity is not valid PEM
+    Tls(String),
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitError::MissingEnv(name) => write!(f, "environment variable {name} is not set"),
+            InitError::Exporter(err) => write!(f, "could not build the OTLP exporter: {err}"),
+            InitError::Tls(reason) => write!(f, "invalid TLS configuration: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for InitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InitError::MissingEnv(_) | InitError::Tls(_) => None,
+            InitError::Exporter(err) => Some(err),
+        }
+    }
+}
+
+impl From<ExporterBuildError> for InitError {
+    fn from(err: ExporterBuildError) -> Self {
+        InitError::Exporter(err)
+    }
+}
+
+/// Check that one of the environment variables `names` is set
+#[cfg(any(feature = "traces", feature = "logs", feature = "metrics"))]
+pub(crate) fn require_env(names: &[&'static str]) -> Result<(), InitError> {
+    if names.iter().any(|name| std::env::var_os(name).is_some()) {
+        Ok(())
+    } else {
+        Err(InitError::MissingEnv(names[0]))
+    }
+}
@@ -1,20 +1,120 @@
 //! Logging of events
 
+use crate::{
+    axum_layer::{DEFAULT_MAX_ATTRIBUTE_LENGTH, ERROR_LOG_TARGET, is_request_span, truncate},
+    dynamic_attributes::{DynamicAttributes, any_value},
+};
 use opentelemetry::{
+    InstrumentationScope, Key, StringValue,
     logs::{AnyValue, LogRecord, Logger, LoggerProvider, Severity},
-    InstrumentationScope, Key,
+    trace::{SpanContext, TraceContextExt},
+};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{
+        Arc, OnceLock, PoisonError, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tracing::{Dispatch, Level, Metadata, dispatcher::WeakDispatch, level_filters::LevelFilter};
+use tracing_core::callsite::Identifier;
+use tracing_opentelemetry::get_otel_context;
+use tracing_subscriber::{
+    Layer,
+    registry::{LookupSpan, SpanRef},
 };
-use std::borrow::Cow;
-use tracing::Level;
-use tracing_subscriber::{registry::LookupSpan, Layer};
 const INSTRUMENTATION_LIBRARY_NAME: &str = "axum_otel_honeycomb";
 
+/// What to use as the body of a log record for an event without a `message` field
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BodyFallback {
+    /// Leave the body empty
+    #[default]
+    Empty,
+    /// The name of the event, eg. `event src/main.rs:42`
+    EventName,
+    /// The first field of the event, as `name=value`
+    FirstField,
+}
+
+/// Format of the `span.<n>` attributes describing the spans an event is in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpanFormat {
+    /// `Name: 'name', { module: 'module', location: 'file:line', field: 'value' }`
+    #[default]
+    Text,
+    /// A JSON object with the keys `name`, `module`, `location` and the span fields,
+    /// which can be picked apart with Honeycomb's JSON functions
+    Json,
+}
+
+/// Where the events fired within a sampled span end up
+///
+/// The `OpenTelemetryLayer` already adds such events to their span as span
+/// events, with the event fields as attributes. Emitting them as log records
+/// as well makes them searchable as logs, but they are sent and billed twice.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EventDestination {
+    /// Emit a log record for every event, in addition to the span event
+    #[default]
+    Both,
+    /// Only keep the span event of events within a sampled span, events
+    /// outside spans or in unsampled traces are still emitted as log records
+    SpanEvents,
+}
+
+/// Which value is kept when an event field and a promoted span field have the
+/// same name, see `AxumOtelEventLogger::with_span_fields`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateKeys {
+    /// Keep the event field, the span field is dropped
+    #[default]
+    EventWins,
+    /// Keep the span field, the event field is dropped
+    SpanWins,
+    /// Keep both, the span field is added as `span.<field>`
+    Namespaced,
+}
+
+type TargetFilter = dyn Fn(&str) -> bool + Send + Sync;
+
+/// Layer forwarding tracing events as `OpenTelemetry` log records.
+///
+/// The subscriber must store spans, which the `LookupSpan` bound of the
+/// `Layer` impl enforces, eg. by building it on `tracing_subscriber::Registry`.
+///
+/// **NOTE**: The `OpenTelemetryLayer` must be part of the same subscriber.
+/// Otherwise the logs are still emitted, but without the trace context, so
+/// they are not correlated with the traces in Honeycomb.
 pub struct AxumOtelEventLogger<P, L>
 where
     P: LoggerProvider<Logger = L> + Send + Sync,
     L: Logger + Send + Sync,
 {
     logger: L,
+    dynamic_attributes: Option<DynamicAttributes>,
+    flusher: Option<Arc<dyn Fn() + Send + Sync>>,
+    flush_level: Option<Level>,
+    body_fallback: BodyFallback,
+    /// sample rate and event counter per level, indexed by `level_index`
+    sampling: [(f64, AtomicU64); 5],
+    span_format: SpanFormat,
+    trace_state: bool,
+    min_level: LevelFilter,
+    allowed_targets: Vec<String>,
+    denied_targets: Vec<String>,
+    target_filter: Option<Arc<TargetFilter>>,
+    event_destination: EventDestination,
+    /// The subscriber the layer is part of, to look up the `OpenTelemetry`
+    /// context of spans
+    dispatch: OnceLock<WeakDispatch>,
+    span_fields: Option<DuplicateKeys>,
+    redacted_fields: Vec<String>,
+    max_attribute_length: usize,
+    truncation_markers: bool,
+    /// the `location` attribute per callsite, formatted by the first event
+    locations: RwLock<HashMap<Identifier, Arc<str>>>,
     _phantom: std::marker::PhantomData<P>, // P is not used.
 }
 
@@ -24,22 +124,264 @@ where
     L: Logger + Send + Sync,
 {
     pub fn new(provider: &P) -> Self {
-        let scope = InstrumentationScope::builder(INSTRUMENTATION_LIBRARY_NAME)
-            .with_version(Cow::Borrowed(env!("CARGO_PKG_VERSION")))
-            .build();
+        Self::with_scope(provider, None, None)
+    }
+
+    /// Create the layer with the instrumentation scope `name` and `version`,
+    /// the defaults are `axum_otel_honeycomb` and the version of this crate.
+    pub fn with_scope(provider: &P, name: Option<String>, version: Option<String>) -> Self {
+        let version = version.map_or(Cow::Borrowed(env!("CARGO_PKG_VERSION")), Cow::Owned);
+        let scope = match name {
+            Some(name) => InstrumentationScope::builder(name),
+            None => InstrumentationScope::builder(INSTRUMENTATION_LIBRARY_NAME),
+        }
+        .with_version(version)
+        .build();
 
         AxumOtelEventLogger {
             logger: provider.logger_with_scope(scope),
+            dynamic_attributes: None,
+            flusher: None,
+            flush_level: None,
+            body_fallback: BodyFallback::default(),
+            sampling: std::array::from_fn(|_| (1.0, AtomicU64::new(0))),
+            span_format: SpanFormat::default(),
+            trace_state: false,
+            min_level: LevelFilter::TRACE,
+            allowed_targets: Vec::new(),
+            denied_targets: Vec::new(),
+            target_filter: None,
+            event_destination: EventDestination::default(),
+            dispatch: OnceLock::new(),
+            span_fields: None,
+            redacted_fields: Vec::new(),
+            max_attribute_length: DEFAULT_MAX_ATTRIBUTE_LENGTH,
+            truncation_markers: false,
+            locations: RwLock::default(),
             _phantom: Default::default(),
         }
     }
+
+    /// Add the W3C `tracestate` of the span the event is in as the attribute
+    /// `trace_state`, when it is not empty.
+    ///
+    /// This helps debugging the propagation between tracing vendors from the logs.
+    #[must_use]
+    pub fn with_trace_state(mut self) -> Self {
+        self.trace_state = true;
+        self
+    }
+
+    /// Only emit the events at `level` or more severe, eg. `LevelFilter::WARN`
+    /// in production.
+    ///
+    /// The default is to emit all the events the subscriber passes on, so it
+    /// works with a `.with_filter()` on the layer as well.
+    #[must_use]
+    pub fn with_min_level(mut self, level: impl Into<LevelFilter>) -> Self {
+        self.min_level = level.into();
+        self
+    }
+
+    /// Only emit the events whose target starts with one of `prefixes`, eg.
+    /// `["my_app"]`.
+    ///
+    /// The target is the module path of the event unless it is given with
+    /// `target:`. Like in `EnvFilter` directives, `my_app` also matches eg.
+    /// `my_app_derive`, use `my_app::` for modules below it only. Add
+    /// `axum_otlp_honeycomb` to keep the logs of `AxumOtelLayer::with_error_logs()`.
+    #[must_use]
+    pub fn with_allowed_targets<I, T>(mut self, prefixes: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.allowed_targets
+            .extend(prefixes.into_iter().map(Into::into));
+        self
+    }
+
+    /// Don't emit the events whose target starts with one of `prefixes`, eg.
+    /// `["hyper", "tower", "sqlx"]`. This wins over `with_allowed_targets()`.
+    #[must_use]
+    pub fn with_denied_targets<I, T>(mut self, prefixes: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.denied_targets
+            .extend(prefixes.into_iter().map(Into::into));
+        self
+    }
+
+    /// Only emit the events for whose target `filter` returns true.
+    ///
+    /// It is checked after the allowed and denied targets, for every event, so
+    /// it must be cheap.
+    #[must_use]
+    pub fn with_target_filter(
+        mut self,
+        filter: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.target_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Decide if an event with `target` should be emitted
+    fn target_enabled(&self, target: &str) -> bool {
+        (self.allowed_targets.is_empty()
+            || self
+                .allowed_targets
+                .iter()
+                .any(|prefix| target.starts_with(prefix.as_str())))
+            && !self
+                .denied_targets
+                .iter()
+                .any(|prefix| target.starts_with(prefix.as_str()))
+            && self
+                .target_filter
+                .as_ref()
+                .is_none_or(|filter| filter(target))
+    }
+
+    /// Set where the events within a span are sent, the default is
+    /// `EventDestination::Both`.
+    ///
+    /// To send events only as log records instead, filter them out of the
+    /// trace layer, eg. `tracing_opentelemetry::layer().with_filter(filter_fn(|meta| meta.is_span()))`.
+    #[must_use]
+    pub fn with_event_destination(mut self, destination: EventDestination) -> Self {
+        self.event_destination = destination;
+        self
+    }
+
+    /// Add the fields of the spans an event is in as attributes of its log
+    /// record, so they can be queried like the event fields.
+    ///
+    /// A field of an inner span takes precedence over the same field of an
+    /// outer span, `duplicates` decides between an event field and a span
+    /// field with the same name.
+    #[must_use]
+    pub fn with_span_fields(mut self, duplicates: DuplicateKeys) -> Self {
+        self.span_fields = Some(duplicates);
+        self
+    }
+
+    /// Record the event and span fields in `names` with the value `[REDACTED]`,
+    /// eg. `["password", "*email*"]`.
+    ///
+    /// A `*` in a name matches any characters, other names must match exactly.
+    /// The value of a redacted field is never formatted, so it does not end up
+    /// in the log record. A redacted `message` gives the body `[REDACTED]`.
+    #[must_use]
+    pub fn with_redacted_fields<I, T>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.redacted_fields
+            .extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Cut the string values of the event fields longer than `max_len` bytes,
+    /// the default is 4096.
+    ///
+    /// The values are cut at a character boundary and end with `…`, so they are
+    /// at most `max_len` bytes. The body (the `message` field) is not cut.
+    #[must_use]
+    pub fn with_max_attribute_length(mut self, max_len: usize) -> Self {
+        self.max_attribute_length = max_len;
+        self
+    }
+
+    /// Add `<field>.truncated = true` next to the values cut by
+    /// `with_max_attribute_length()`.
+    #[must_use]
+    pub fn with_truncation_markers(mut self) -> Self {
+        self.truncation_markers = true;
+        self
+    }
+
+    /// Set the format of the `span.<n>` attributes, the default is `SpanFormat::Text`.
+    #[must_use]
+    pub fn with_span_format(mut self, format: SpanFormat) -> Self {
+        self.span_format = format;
+        self
+    }
+
+    /// Only emit the fraction `rate` of the events at `level`, eg. to keep
+    /// 10% of the INFO logs while all WARN and ERROR logs are emitted.
+    ///
+    /// The sampling is deterministic: with a rate of 0.1 every tenth event
+    /// at the level is emitted. The rate is clamped to `0.0..=1.0`, the
+    /// default is 1.0 for all levels.
+    #[must_use]
+    pub fn with_level_sample_rate(mut self, level: Level, rate: f64) -> Self {
+        self.sampling[level_index(&level)].0 = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Decide if an event at `level` should be emitted
+    fn sample(&self, level: &Level) -> bool {
+        let (rate, counter) = &self.sampling[level_index(level)];
+        if *rate >= 1.0 {
+            return true;
+        }
+        // keep the events where the running total of `rate` passes an integer
+        #[allow(clippy::cast_precision_loss)]
+        let n = counter.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * rate).floor() > (n * rate).floor()
+    }
+
+    /// Set the body of log records for events without a `message` field,
+    /// so the Honeycomb log view isn't blank for purely structured events.
+    #[must_use]
+    pub fn with_body_fallback(mut self, fallback: BodyFallback) -> Self {
+        self.body_fallback = fallback;
+        self
+    }
+
+    /// Set the function used to flush the logger provider, see `with_flush_level`.
+    ///
+    /// `init_otlp_log_layer()` sets it to `force_flush()` on its provider.
+    #[must_use]
+    pub fn with_flusher(mut self, flusher: impl Fn() + Send + Sync + 'static) -> Self {
+        self.flusher = Some(Arc::new(flusher));
+        self
+    }
+
+    /// Flush the logs after emitting an event at or above `level`.
+    ///
+    /// Batching logs means the last ERROR before a crash may never reach
+    /// Honeycomb. With eg. `Level::ERROR` these records are exported
+    /// before `on_event` returns, at the cost of blocking the logging
+    /// thread while the batch is sent.
+    #[must_use]
+    pub fn with_flush_level(mut self, level: Level) -> Self {
+        self.flush_level = Some(level);
+        self
+    }
+
+    /// Add the current values of `attributes` to every log record.
+    #[must_use]
+    pub fn with_dynamic_attributes(mut self, attributes: DynamicAttributes) -> Self {
+        self.dynamic_attributes = Some(attributes);
+        self
+    }
 }
 
-/// All data and metadata from the span.
+/// Marks a request span in which an ERROR event has been logged.
+struct ErrorLogged;
+
+/// All data and metadata from the span, the strings are shared by the log
+/// records of the events in the span.
 #[derive(Debug)]
 struct ExtensionValues {
-    span_str: String,
-    location: String,
+    span_str: Arc<str>,
+    location: Arc<str>,
+    /// the span fields, for `with_span_fields`
+    fields: Vec<(&'static str, AnyValue)>,
 }
 
 impl<S, P, L> Layer<S> for AxumOtelEventLogger<P, L>
@@ -48,69 +390,301 @@ where
     P: LoggerProvider<Logger = L> + Send + Sync + 'static,
     L: Logger + Send + Sync + 'static,
 {
+    fn on_register_dispatch(&self, subscriber: &Dispatch) {
+        let _ = self.dispatch.set(subscriber.downgrade());
+    }
+
     fn on_new_span(
         &self,
         attrs: &tracing_core::span::Attributes<'_>,
         id: &tracing_core::span::Id,
         ctx: tracing_subscriber::layer::Context<'_, S>,
     ) {
-        if let Some(span) = ctx.span(id) {
-            let mut span_str = String::with_capacity(256);
-            let location = format!(
-                "{file}:{line}",
-                file = attrs.metadata().file().unwrap_or("UNKNOWN"),
-                line = attrs.metadata().line().unwrap_or_default(),
-            );
-            span_str.push_str(&format!(
-                "Name: '{name}', {{ module: '{module}', location: '{location}'",
-                name = attrs.metadata().name(),
-                module = attrs.metadata().module_path().unwrap_or_default(),
-                location = location,
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut span_str = String::with_capacity(256);
+        let location = format!(
+            "{file}:{line}",
+            file = attrs.metadata().file().unwrap_or("UNKNOWN"),
+            line = attrs.metadata().line().unwrap_or_default(),
+        );
+        let name = attrs.metadata().name();
+        let module = attrs.metadata().module_path().unwrap_or_default();
+        match self.span_format {
+            SpanFormat::Text => span_str.push_str(&format!(
+                "Name: '{name}', {{ module: '{module}', location: '{location}'"
+            )),
+            SpanFormat::Json => span_str.push_str(&format!(
+                r#"{{"name":{},"module":{},"location":{}"#,
+                json_string(name),
+                json_string(module),
+                json_string(&location),
+            )),
+        }
+
+        let mut visitor = SpanVisitor::new(&mut span_str, self.span_format);
+        attrs.values().record(&mut RedactingVisitor::new(
+            &mut visitor,
+            &self.redacted_fields,
+        ));
+        span_str.push_str(match self.span_format {
+            SpanFormat::Text => " }",
+            SpanFormat::Json => "}",
+        });
+        let mut fields = Vec::new();
+        if self.span_fields.is_some() {
+            attrs.values().record(&mut RedactingVisitor::new(
+                &mut FieldsVisitor(&mut fields),
+                &self.redacted_fields,
             ));
+        }
+        let extension = ExtensionValues {
+            span_str: span_str.into(),
+            location: location.into(),
+            fields,
+        };
+        span.extensions_mut().insert(extension);
+    }
 
-            let mut visitor = SpanVisitor::new(&mut span_str);
-            attrs.values().record(&mut visitor);
-            span_str.push_str(" }");
-            let extension = ExtensionValues { span_str, location };
-            span.extensions_mut().insert(extension);
+    fn on_record(
+        &self,
+        id: &tracing_core::span::Id,
+        values: &tracing_core::span::Record<'_>,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        if self.span_fields.is_some()
+            && let Some(span) = ctx.span(id)
+            && let Some(extension) = span.extensions_mut().get_mut::<ExtensionValues>()
+        {
+            values.record(&mut RedactingVisitor::new(
+                &mut FieldsVisitor(&mut extension.fields),
+                &self.redacted_fields,
+            ));
         }
     }
 
     fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
         let meta = event.metadata();
+        if *meta.level() > self.min_level
+            || !self.target_enabled(meta.target())
+            || !self.sample(meta.level())
+        {
+            return;
+        }
+        // Drop the error log of a failed request when the handler already logged an error
+        if *meta.level() == Level::ERROR
+            && let Some(request) = ctx
+                .event_scope(event)
+                .and_then(|mut scope| scope.find(|span| is_request_span(span.metadata())))
+        {
+            if meta.target() == ERROR_LOG_TARGET {
+                if request.extensions().get::<ErrorLogged>().is_some() {
+                    return;
+                }
+            } else {
+                request.extensions_mut().insert(ErrorLogged);
+            }
+        }
+
+        let span_context = ctx
+            .event_span(event)
+            .and_then(|span| span_context(&span, self.dispatch.get()?));
+        // The trace layer has recorded the event as a span event already
+        if self.event_destination == EventDestination::SpanEvents
+            && span_context.as_ref().is_some_and(SpanContext::is_sampled)
+        {
+            return;
+        }
 
         let mut log_record = self.logger.create_log_record();
 
-        // TODO: Fix heap allocation
-        log_record.set_target(meta.target().to_string());
+        log_record.set_target(meta.target());
         log_record.set_event_name(meta.name());
         log_record.set_severity_number(severity_of_level(meta.level()));
         log_record.set_severity_text(meta.level().as_str());
-        log_record.add_attribute(
-            "location",
-            format!(
-                "{}:{}",
-                meta.file().unwrap_or("UNKNOWN"),
-                meta.line().unwrap_or_default()
-            ),
+        log_record.add_attribute("location", StringValue::from(self.location(meta)));
+        let span_fields = self.promoted_span_fields(event, &ctx);
+        // the event fields replaced by span fields
+        let skipped: Vec<&str> = if self.span_fields == Some(DuplicateKeys::SpanWins) {
+            span_fields.iter().map(|(key, _)| *key).collect()
+        } else {
+            Vec::new()
+        };
+        let mut visitor = EventVisitor::new(
+            &mut log_record,
+            self.body_fallback == BodyFallback::FirstField,
+            &skipped,
+            (self.max_attribute_length, self.truncation_markers),
         );
-        let mut visitor = EventVisitor::new(&mut log_record);
         // Visit fields.
-        event.record(&mut visitor);
+        event.record(&mut RedactingVisitor::new(
+            &mut visitor,
+            &self.redacted_fields,
+        ));
+        if !visitor.has_body {
+            match (self.body_fallback, visitor.first_field.take()) {
+                (BodyFallback::EventName, _) => log_record.set_body(meta.name().into()),
+                (BodyFallback::FirstField, Some(first)) => log_record.set_body(first.into()),
+                _ => {}
+            }
+        }
+        for (key, value) in span_fields {
+            let is_duplicate = meta.fields().field(key).is_some();
+            match self.span_fields {
+                Some(DuplicateKeys::EventWins) if is_duplicate => {}
+                Some(DuplicateKeys::Namespaced) if is_duplicate => {
+                    log_record.add_attribute(format!("span.{key}"), value);
+                }
+                _ => log_record.add_attribute(Key::new(key), value),
+            }
+        }
         // Log spans.
         if let Some(scope) = ctx.event_scope(event) {
             for (i, span) in scope.from_root().enumerate() {
                 let ext = span.extensions();
+                let [span_key, location_key, name_key] = span_keys(i);
                 if let Some(span_data) = ext.get::<ExtensionValues>() {
-                    log_record.add_attribute(format!("span.{i}"), span_data.span_str.clone());
                     log_record
-                        .add_attribute(format!("span.{i}.location"), span_data.location.clone());
+                        .add_attribute(span_key, StringValue::from(span_data.span_str.clone()));
+                    log_record
+                        .add_attribute(location_key, StringValue::from(span_data.location.clone()));
+                }
+                log_record.add_attribute(name_key, span.name());
+            }
+        }
+        // Correlate with the trace of the span the event is in
+        if let Some(span_context) = span_context {
+            log_record.set_trace_context(
+                span_context.trace_id(),
+                span_context.span_id(),
+                Some(span_context.trace_flags()),
+            );
+            if self.trace_state {
+                let trace_state = span_context.trace_state().header();
+                if !trace_state.is_empty() {
+                    log_record.add_attribute("trace_state", trace_state);
                 }
-                log_record.add_attribute(format!("span.{i}.name"), span.name());
             }
         }
+        if let Some(attributes) = &self.dynamic_attributes {
+            attributes
+                .for_each(|key, value| log_record.add_attribute(key.clone(), any_value(value)));
+        }
         //emit record
         self.logger.emit(log_record);
+        // tracing levels are ordered by verbosity, ERROR is the smallest
+        if let (Some(level), Some(flush)) = (&self.flush_level, &self.flusher)
+            && meta.level() <= level
+        {
+            flush();
+        }
+    }
+}
+
+impl<P, L> AxumOtelEventLogger<P, L>
+where
+    P: LoggerProvider<Logger = L> + Send + Sync,
+    L: Logger + Send + Sync,
+{
+    /// The `file:line` of the callsite of an event, formatted once per callsite
+    fn location(&self, meta: &'static Metadata<'static>) -> Arc<str> {
+        let callsite = meta.callsite();
+        if let Some(location) = self
+            .locations
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&callsite)
+        {
+            return location.clone();
+        }
+        let location: Arc<str> = format!(
+            "{}:{}",
+            meta.file().unwrap_or("UNKNOWN"),
+            meta.line().unwrap_or_default()
+        )
+        .into();
+        self.locations
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(callsite)
+            .or_insert(location)
+            .clone()
+    }
+
+    /// The fields of the spans `event` is in, for `with_span_fields`, a field
+    /// of an inner span hides the same field of an outer span
+    fn promoted_span_fields<S>(
+        &self,
+        event: &tracing::Event<'_>,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+    ) -> Vec<(&'static str, AnyValue)>
+    where
+        S: tracing::Subscriber + for<'lookup> LookupSpan<'lookup>,
+    {
+        let mut fields: Vec<(&'static str, AnyValue)> = Vec::new();
+        if self.span_fields.is_none() {
+            return fields;
+        }
+        for span in ctx.event_scope(event).into_iter().flatten() {
+            if let Some(extension) = span.extensions().get::<ExtensionValues>() {
+                for (key, value) in &extension.fields {
+                    if !fields.iter().any(|(seen, _)| seen == key) {
+                        fields.push((key, value.clone()));
+                    }
+                }
+            }
+        }
+        fields
+    }
+}
+
+/// The `OpenTelemetry` span context of a tracing span, if it is valid.
+///
+/// The context is stored in the span extensions by the `OpenTelemetryLayer`,
+/// so this is `None` when that layer is not part of the subscriber.
+fn span_context<'a, S>(span: &SpanRef<'a, S>, dispatch: &WeakDispatch) -> Option<SpanContext>
+where
+    S: LookupSpan<'a>,
+{
+    // `dispatcher::get_default` can't be used here, it is the no-op
+    // dispatcher while the subscriber handles an event
+    let dispatch = dispatch.upgrade()?;
+    let cx = get_otel_context(&mut span.extensions_mut(), &dispatch)?;
+    let span_context = cx.span().span_context().clone();
+    span_context.is_valid().then_some(span_context)
+}
+
+/// The keys of the attributes describing the span at depth `i`, the common
+/// depths don't need formatting
+fn span_keys(i: usize) -> [Key; 3] {
+    const KEYS: [[&str; 3]; 8] = [
+        ["span.0", "span.0.location", "span.0.name"],
+        ["span.1", "span.1.location", "span.1.name"],
+        ["span.2", "span.2.location", "span.2.name"],
+        ["span.3", "span.3.location", "span.3.name"],
+        ["span.4", "span.4.location", "span.4.name"],
+        ["span.5", "span.5.location", "span.5.name"],
+        ["span.6", "span.6.location", "span.6.name"],
+        ["span.7", "span.7.location", "span.7.name"],
+    ];
+    match KEYS.get(i) {
+        Some(keys) => keys.map(Key::from_static_str),
+        None => [
+            Key::new(format!("span.{i}")),
+            Key::new(format!("span.{i}.location")),
+            Key::new(format!("span.{i}.name")),
+        ],
+    }
+}
+
+const fn level_index(level: &Level) -> usize {
+    match *level {
+        Level::TRACE => 0,
+        Level::DEBUG => 1,
+        Level::INFO => 2,
+        Level::WARN => 3,
+        Level::ERROR => 4,
     }
 }
 
@@ -127,11 +701,55 @@ const fn severity_of_level(level: &Level) -> Severity {
 /// Visitor to record the fields from the event record.
 struct EventVisitor<'a, LR: LogRecord> {
     log_record: &'a mut LR,
+    /// the `message` field has been recorded as the body
+    has_body: bool,
+    /// keep the first field for `BodyFallback::FirstField`
+    capture_first_field: bool,
+    first_field: Option<String>,
+    /// fields not to add, because a span field of the same name wins
+    skipped: &'a [&'a str],
+    /// the longest string value, and whether to mark the values that were cut
+    truncation: (usize, bool),
 }
 
 impl<'a, LR: LogRecord> EventVisitor<'a, LR> {
-    fn new(log_record: &'a mut LR) -> Self {
-        EventVisitor { log_record }
+    fn new(
+        log_record: &'a mut LR,
+        capture_first_field: bool,
+        skipped: &'a [&'a str],
+        truncation: (usize, bool),
+    ) -> Self {
+        EventVisitor {
+            log_record,
+            has_body: false,
+            capture_first_field,
+            first_field: None,
+            skipped,
+            truncation,
+        }
+    }
+
+    fn add(&mut self, field: &tracing::field::Field, value: AnyValue) {
+        if !self.skipped.contains(&field.name()) {
+            self.log_record.add_attribute(Key::new(field.name()), value);
+        }
+    }
+
+    /// Add a string value, cut to the maximum length
+    fn add_string(&mut self, field: &tracing::field::Field, mut value: String) {
+        let (max_len, markers) = self.truncation;
+        let truncated = truncate(&mut value, max_len);
+        self.add(field, AnyValue::from(value));
+        if truncated && markers && !self.skipped.contains(&field.name()) {
+            self.log_record
+                .add_attribute(format!("{}.truncated", field.name()), true);
+        }
+    }
+
+    fn capture(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if self.capture_first_field && self.first_field.is_none() {
+            self.first_field = Some(format!("{}={value:?}", field.name()));
+        }
     }
 }
 
@@ -139,78 +757,375 @@ impl<LR: LogRecord> tracing::field::Visit for EventVisitor<'_, LR> {
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
         if field.name() == "message" {
             self.log_record.set_body(format!("{:?}", value).into());
+            self.has_body = true;
         } else {
-            self.log_record
-                .add_attribute(Key::new(field.name()), AnyValue::from(format!("{value:?}")));
+            self.capture(field, value);
+            self.add_string(field, format!("{value:?}"));
         }
     }
 
     fn record_str(&mut self, field: &tracing_core::Field, value: &str) {
-        //TODO: Consider special casing "message" to populate body and document
-        // to users to use message field for log message, to avoid going to the
-        // record_debug, which has dyn dispatch, string allocation and
-        // formatting cost.
+        if field.name() == "message" {
+            self.log_record.set_body(value.to_owned().into());
+            self.has_body = true;
+            return;
+        }
+        self.capture(field, &value);
+        self.add_string(field, value.to_owned());
+    }
+
+    fn record_bool(&mut self, field: &tracing_core::Field, value: bool) {
+        self.capture(field, &value);
+        self.add(field, AnyValue::from(value));
+    }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.capture(field, &value);
+        self.add(field, AnyValue::from(value));
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.capture(field, &value);
+        self.add(field, AnyValue::from(value));
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.capture(field, &value);
+        self.add(field, int_or_string(value));
+    }
+
+    fn record_i128(&mut self, field: &tracing::field::Field, value: i128) {
+        self.capture(field, &value);
+        self.add(field, int_or_string(value));
+    }
+
+    fn record_u128(&mut self, field: &tracing::field::Field, value: u128) {
+        self.capture(field, &value);
+        self.add(field, int_or_string(value));
+    }
+
+    fn record_bytes(&mut self, field: &tracing::field::Field, value: &[u8]) {
+        self.capture(field, &value);
+        self.add(field, AnyValue::Bytes(Box::new(value.to_vec())));
+    }
+
+    // TODO: Remaining field types from AnyValue : ListAny
+}
+
+/// An integer as `AnyValue::Int`, or as a string if it doesn't fit in an `i64`
+fn int_or_string<T>(value: T) -> AnyValue
+where
+    T: TryInto<i64> + ToString + Copy,
+{
+    value
+        .try_into()
+        .map_or_else(|_| AnyValue::from(value.to_string()), AnyValue::Int)
+}
+
+/// Visitor passing the fields on to `inner`, the fields matching one of the
+/// `redacted` names are passed on as `[REDACTED]`
+struct RedactingVisitor<'a, V> {
+    inner: &'a mut V,
+    redacted: &'a [String],
+}
+
+impl<'a, V: tracing::field::Visit> RedactingVisitor<'a, V> {
+    fn new(inner: &'a mut V, redacted: &'a [String]) -> Self {
+        RedactingVisitor { inner, redacted }
+    }
+
+    /// Pass on `[REDACTED]` if `field` is redacted, returns false otherwise
+    fn redact(&mut self, field: &tracing::field::Field) -> bool {
+        let redacted = self
+            .redacted
+            .iter()
+            .any(|pattern| glob_match(pattern, field.name()));
+        if redacted {
+            self.inner.record_debug(field, &format_args!("[REDACTED]"));
+        }
+        redacted
+    }
+}
+
+impl<V: tracing::field::Visit> tracing::field::Visit for RedactingVisitor<'_, V> {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if !self.redact(field) {
+            self.inner.record_debug(field, value);
+        }
+    }
+
+    fn record_str(&mut self, field: &tracing_core::Field, value: &str) {
+        if !self.redact(field) {
+            self.inner.record_str(field, value);
+        }
+    }
+
+    fn record_bool(&mut self, field: &tracing_core::Field, value: bool) {
+        if !self.redact(field) {
+            self.inner.record_bool(field, value);
+        }
+    }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        if !self.redact(field) {
+            self.inner.record_f64(field, value);
+        }
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        if !self.redact(field) {
+            self.inner.record_i64(field, value);
+        }
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        if !self.redact(field) {
+            self.inner.record_u64(field, value);
+        }
+    }
+
+    fn record_i128(&mut self, field: &tracing::field::Field, value: i128) {
+        if !self.redact(field) {
+            self.inner.record_i128(field, value);
+        }
+    }
+
+    fn record_u128(&mut self, field: &tracing::field::Field, value: u128) {
+        if !self.redact(field) {
+            self.inner.record_u128(field, value);
+        }
+    }
+
+    fn record_bytes(&mut self, field: &tracing::field::Field, value: &[u8]) {
+        if !self.redact(field) {
+            self.inner.record_bytes(field, value);
+        }
+    }
+
+    fn record_error(
+        &mut self,
+        field: &tracing::field::Field,
+        value: &(dyn std::error::Error + 'static),
+    ) {
+        if !self.redact(field) {
+            self.inner.record_error(field, value);
+        }
+    }
+}
 
-        //TODO: Fix heap allocation. Check if lifetime of &str can be used
-        // to optimize sync exporter scenario.
-        self.log_record
-            .add_attribute(Key::new(field.name()), AnyValue::from(value.to_owned()));
+/// Whether `name` matches `pattern`, where `*` matches any characters
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let Some((head, tail)) = pattern.split_once('*') else {
+        return pattern == name;
+    };
+    let (middle, last) = tail.rsplit_once('*').unwrap_or(("", tail));
+    let Some(mut rest) = name.strip_prefix(head) else {
+        return false;
+    };
+    for part in middle.split('*').filter(|part| !part.is_empty()) {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Visitor to keep the span fields as attribute values, a field recorded
+/// again replaces the previous value
+struct FieldsVisitor<'a>(&'a mut Vec<(&'static str, AnyValue)>);
+
+impl FieldsVisitor<'_> {
+    fn set(&mut self, field: &tracing::field::Field, value: AnyValue) {
+        match self.0.iter_mut().find(|(key, _)| *key == field.name()) {
+            Some((_, previous)) => *previous = value,
+            None => self.0.push((field.name(), value)),
+        }
+    }
+}
+
+impl tracing::field::Visit for FieldsVisitor<'_> {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.set(field, AnyValue::from(format!("{value:?}")));
+    }
+
+    fn record_str(&mut self, field: &tracing_core::Field, value: &str) {
+        self.set(field, AnyValue::from(value.to_owned()));
     }
 
     fn record_bool(&mut self, field: &tracing_core::Field, value: bool) {
-        self.log_record
-            .add_attribute(Key::new(field.name()), AnyValue::from(value));
+        self.set(field, AnyValue::from(value));
     }
 
     fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
-        self.log_record
-            .add_attribute(Key::new(field.name()), AnyValue::from(value));
+        self.set(field, AnyValue::from(value));
     }
 
     fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
-        self.log_record
-            .add_attribute(Key::new(field.name()), AnyValue::from(value));
+        self.set(field, AnyValue::from(value));
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.set(field, int_or_string(value));
+    }
+
+    fn record_i128(&mut self, field: &tracing::field::Field, value: i128) {
+        self.set(field, int_or_string(value));
+    }
+
+    fn record_u128(&mut self, field: &tracing::field::Field, value: u128) {
+        self.set(field, int_or_string(value));
     }
 
-    // TODO: Remaining field types from AnyValue : Bytes, ListAny, Boolean
+    fn record_bytes(&mut self, field: &tracing::field::Field, value: &[u8]) {
+        self.set(field, AnyValue::Bytes(Box::new(value.to_vec())));
+    }
 }
 
 /// Visitor to record the fields from the event record.
 struct SpanVisitor<'a> {
     extension_values: &'a mut String,
+    format: SpanFormat,
 }
 
 impl<'a> SpanVisitor<'a> {
-    fn new(extension_values: &'a mut String) -> Self {
-        SpanVisitor { extension_values }
+    fn new(extension_values: &'a mut String, format: SpanFormat) -> Self {
+        SpanVisitor {
+            extension_values,
+            format,
+        }
+    }
+
+    /// Add a field, `json` gives the value as a JSON value
+    fn push(
+        &mut self,
+        field: &tracing::field::Field,
+        text: &dyn std::fmt::Display,
+        json: impl FnOnce() -> String,
+    ) {
+        match self.format {
+            SpanFormat::Text => {
+                self.extension_values
+                    .push_str(&format!(", {}: '{}'", field.name(), text))
+            }
+            SpanFormat::Json => self.extension_values.push_str(&format!(
+                ",{}:{}",
+                json_string(field.name()),
+                json()
+            )),
+        }
     }
 }
 
 impl tracing::field::Visit for SpanVisitor<'_> {
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-        self.extension_values
-            .push_str(&format!(", {}: '{:?}'", field.name(), value))
+        let value = format!("{value:?}");
+        self.push(field, &value, || json_string(&value));
     }
 
     fn record_str(&mut self, field: &tracing_core::Field, value: &str) {
-        self.extension_values
-            .push_str(&format!(", {}: '{}'", field.name(), value))
+        self.push(field, &value, || json_string(value));
     }
 
     fn record_bool(&mut self, field: &tracing_core::Field, value: bool) {
-        self.extension_values
-            .push_str(&format!(", {}: '{}'", field.name(), value))
+        self.push(field, &value, || value.to_string());
     }
 
     fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
-        self.extension_values
-            .push_str(&format!(", {}: '{}'", field.name(), value))
+        self.push(field, &value, || {
+            if value.is_finite() {
+                value.to_string()
+            } else {
+                json_string(&value.to_string())
+            }
+        });
     }
 
     fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
-        self.extension_values
-            .push_str(&format!(", {}: '{}'", field.name(), value))
+        self.push(field, &value, || value.to_string());
+    }
+}
+
+/// Quote and escape a string as a JSON string
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn int_or_string_keeps_integers_that_fit_in_i64() {
+        assert_eq!(int_or_string(42u64), AnyValue::Int(42));
+        assert_eq!(int_or_string(-42i128), AnyValue::Int(-42));
+        assert_eq!(
+            int_or_string(u128::from(u32::MAX)),
+            AnyValue::Int(4_294_967_295)
+        );
+    }
+
+    #[test]
+    fn int_or_string_formats_integers_out_of_range() {
+        assert_eq!(
+            int_or_string(u64::MAX),
+            AnyValue::from("18446744073709551615")
+        );
+        assert_eq!(
+            int_or_string(i128::MIN),
+            AnyValue::from(i128::MIN.to_string())
+        );
+    }
+
+    #[test]
+    fn glob_match_star_matches_everything() {
+        assert!(glob_match("*", "password"));
+        assert!(glob_match("*", ""));
     }
 
-    // TODO: Remaining field types from AnyValue : Bytes, ListAny, Boolean
+    #[test]
+    fn glob_match_prefix() {
+        assert!(glob_match("auth*", "authorization"));
+        assert!(glob_match("auth*", "auth"));
+        assert!(!glob_match("auth*", "oauth"));
+    }
+
+    #[test]
+    fn glob_match_suffix() {
+        assert!(glob_match("*_token", "refresh_token"));
+        assert!(glob_match("*_token", "_token"));
+        assert!(!glob_match("*_token", "token_id"));
+    }
+
+    #[test]
+    fn glob_match_several_stars() {
+        assert!(glob_match("a*b*c", "abc"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "acb"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+        assert!(glob_match("*email*", "user_email_address"));
+        // the prefix and the suffix don't overlap
+        assert!(!glob_match("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn glob_match_without_star_is_exact() {
+        assert!(glob_match("password", "password"));
+        assert!(!glob_match("password", "password_hash"));
+        assert!(!glob_match("password", "Password"));
+    }
 }
@@ -0,0 +1,173 @@
+//! Handle on the providers behind the layers
+
+#[cfg(all(feature = "traces", feature = "logs"))]
+use crate::SampleRateHandle;
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+#[cfg(feature = "metrics")]
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+#[cfg(all(feature = "traces", feature = "logs"))]
+use opentelemetry_sdk::{logs::SdkLoggerProvider, trace::SdkTracerProvider};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(all(feature = "traces", feature = "logs"))]
+/// The tracer and logger providers created by `init()`
+///
+/// Hold it in `main` until the program ends: when it is dropped the batched
+/// spans and logs are flushed and the providers are shut down, so short-lived
+/// processes don't lose their last batch.
+///
+/// Use `flush()` to make sure the batched spans and logs are exported at a
+/// checkpoint, eg. after a critical operation or at the end of a test.
+#[derive(Debug)]
+pub struct OtelGuard {
+    tracer_provider: SdkTracerProvider,
+    logger_provider: SdkLoggerProvider,
+    sample_rate: SampleRateHandle,
+    is_shutdown: AtomicBool,
+}
+
+#[cfg(all(feature = "traces", feature = "logs"))]
+impl OtelGuard {
+    pub(crate) fn new(
+        tracer_provider: SdkTracerProvider,
+        logger_provider: SdkLoggerProvider,
+        sample_rate: SampleRateHandle,
+    ) -> Self {
+        OtelGuard {
+            tracer_provider,
+            logger_provider,
+            sample_rate,
+            is_shutdown: AtomicBool::new(false),
+        }
+    }
+
+    /// Handle to change the sample rate of the tracer provider while it runs
+    #[must_use]
+    pub fn sample_rate(&self) -> &SampleRateHandle {
+        &self.sample_rate
+    }
+
+    /// Export the batched spans and logs, blocking until it is done.
+    ///
+    /// This blocks the thread, so in async code use `flush_async()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error from flushing the tracer or the logger provider,
+    /// both are flushed anyway.
+    pub fn flush(&self) -> OTelSdkResult {
+        flush(&self.tracer_provider, &self.logger_provider)
+    }
+
+    /// Export the batched spans and logs like `flush()`, on a blocking thread
+    /// of the tokio runtime.
+    ///
+    /// # Errors
+    ///
+    /// As for `flush()`.
+    ///
+    /// # Panics
+    ///
+    /// If it is not called from within a tokio runtime.
+    pub async fn flush_async(&self) -> OTelSdkResult {
+        let tracer_provider = self.tracer_provider.clone();
+        let logger_provider = self.logger_provider.clone();
+        tokio::task::spawn_blocking(move || flush(&tracer_provider, &logger_provider))
+            .await
+            .unwrap_or_else(|err| Err(OTelSdkError::InternalFailure(err.to_string())))
+    }
+
+    /// Flush and shut down the providers. Spans and logs created afterwards are dropped.
+    ///
+    /// This is done when the guard is dropped, call it to see the errors.
+    /// Only the first call does anything, later calls return `Ok(())`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error from flushing or shutting down the providers.
+    pub fn shutdown(&self) -> OTelSdkResult {
+        if self.is_shutdown.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let flushed = self.flush();
+        let traces = already_shutdown_is_ok(self.tracer_provider.shutdown());
+        let logs = already_shutdown_is_ok(self.logger_provider.shutdown());
+        flushed.and(traces).and(logs)
+    }
+}
+
+#[cfg(all(feature = "traces", feature = "logs"))]
+impl Drop for OtelGuard {
+    fn drop(&mut self) {
+        // a panic while the program unwinds would abort it, so never let one out
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.shutdown()));
+    }
+}
+
+#[cfg(feature = "metrics")]
+/// The meter provider created by `init_otlp_metrics()`
+///
+/// Hold it in `main` until the program ends: when it is dropped the metrics
+/// are exported and the provider is shut down.
+#[derive(Debug)]
+pub struct MetricsGuard {
+    meter_provider: SdkMeterProvider,
+    is_shutdown: AtomicBool,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsGuard {
+    pub(crate) fn new(meter_provider: SdkMeterProvider) -> Self {
+        MetricsGuard {
+            meter_provider,
+            is_shutdown: AtomicBool::new(false),
+        }
+    }
+
+    /// Export the metrics collected so far, blocking until it is done.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from flushing the meter provider.
+    pub fn flush(&self) -> OTelSdkResult {
+        self.meter_provider.force_flush()
+    }
+
+    /// Flush and shut down the meter provider, like `OtelGuard::shutdown()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error from flushing or shutting down the provider.
+    pub fn shutdown(&self) -> OTelSdkResult {
+        if self.is_shutdown.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let flushed = self.flush();
+        flushed.and(already_shutdown_is_ok(self.meter_provider.shutdown()))
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Drop for MetricsGuard {
+    fn drop(&mut self) {
+        // a panic while the program unwinds would abort it, so never let one out
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.shutdown()));
+    }
+}
+
+#[cfg(all(feature = "traces", feature = "logs"))]
+fn flush(
+    tracer_provider: &SdkTracerProvider,
+    logger_provider: &SdkLoggerProvider,
+) -> OTelSdkResult {
+    let traces = tracer_provider.force_flush();
+    let logs = logger_provider.force_flush();
+    traces.and(logs)
+}
+
+fn already_shutdown_is_ok(result: OTelSdkResult) -> OTelSdkResult {
+    match result {
+        Err(OTelSdkError::AlreadyShutdown) => Ok(()),
+        result => result,
+    }
+}
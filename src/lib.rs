@@ -1,73 +1,425 @@
 //! Crate for connecting tracing in Axum via the Opengtelemetry-otlp
 //! protocol to Honeycomb.
 
+#[cfg(any(feature = "traces", feature = "logs", feature = "metrics"))]
+use error::require_env;
+#[cfg(feature = "logs")]
 use event_logger::AxumOtelEventLogger;
-use opentelemetry::trace::TracerProvider as _;
-use opentelemetry_otlp::{LogExporter, SpanExporter};
-use opentelemetry_sdk::{
-    self as sdk,
-    logs::{SdkLogger, SdkLoggerProvider},
-    trace::{Sampler, Tracer},
+#[cfg(any(feature = "traces", feature = "logs", feature = "metrics"))]
+use opentelemetry::KeyValue;
+use opentelemetry::propagation::{TextMapCompositePropagator, TextMapPropagator};
+#[cfg(feature = "traces")]
+use opentelemetry::{InstrumentationScope, trace::TracerProvider as _};
+#[cfg(feature = "logs")]
+use opentelemetry_otlp::LogExporter;
+#[cfg(feature = "metrics")]
+use opentelemetry_otlp::MetricExporter;
+#[cfg(feature = "traces")]
+use opentelemetry_otlp::SpanExporter;
+#[cfg(any(feature = "traces", feature = "logs", feature = "metrics"))]
+use opentelemetry_otlp::{
+    WithExportConfig, WithHttpConfig, WithTonicConfig, tonic_types::metadata::MetadataMap,
 };
+#[cfg(any(feature = "traces", feature = "logs", feature = "metrics"))]
+use opentelemetry_sdk::Resource;
+#[cfg(feature = "logs")]
+use opentelemetry_sdk::logs::{self, BatchLogProcessor, SdkLogger, SdkLoggerProvider};
+#[cfg(feature = "metrics")]
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::propagation::{BaggagePropagator, TraceContextPropagator};
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::trace::{self, BatchSpanProcessor, SdkTracerProvider, Tracer};
+#[cfg(feature = "traces")]
+use processor::FanoutProcessor;
+#[cfg(feature = "traces")]
+use sampler::ParentBasedSampler;
+#[cfg(feature = "traces")]
+pub use sampler::SampleRateHandle;
+#[cfg(any(feature = "traces", feature = "logs", feature = "metrics"))]
+use std::collections::HashMap;
+#[cfg(feature = "traces")]
+use std::time::Duration;
+#[cfg(feature = "traces")]
 use tracing_core::Subscriber;
+#[cfg(feature = "traces")]
 use tracing_opentelemetry::OpenTelemetryLayer;
+#[cfg(feature = "traces")]
 use tracing_subscriber::registry::LookupSpan;
 mod axum_layer;
+mod child_span;
+mod config;
+mod current;
+mod dynamic_attributes;
+mod error;
+#[cfg(feature = "logs")]
 mod event_logger;
-pub use axum_layer::{opentelemetry_tracing_layer, opentelemetry_tracing_layer_without_parent};
+#[cfg(any(all(feature = "traces", feature = "logs"), feature = "metrics"))]
+mod guard;
+mod links;
+mod metrics;
+mod panic;
+#[cfg(feature = "traces")]
+mod processor;
+mod rate_limit;
+mod response_body;
+#[cfg(feature = "traces")]
+mod sampler;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(any(feature = "traces", feature = "logs", feature = "metrics"))]
+mod tls;
+pub use axum_layer::{
+    AcceptLanguage, ClientErrorStatus, Fallback, ForwardedFor, Http2StreamId, JwtClaims,
+    OperationId, RateLimit, RequestIdMode, ResendCount, SpanName, TlsServerName,
+    opentelemetry_tracing_layer, opentelemetry_tracing_layer_without_parent, user_agent_category,
+};
+pub use child_span::instrument_child;
+#[cfg(feature = "traces")]
+pub use config::TracesExporter;
+pub use config::{
+    Compression, HoneycombRegion, OtlpConfig, OtlpConfigBuilder, Propagator, Protocol,
+    SamplerChoice,
+};
+pub use current::{current_span_id, current_trace_id};
+pub use dynamic_attributes::DynamicAttributes;
+pub use error::InitError;
+#[cfg(feature = "logs")]
+pub use event_logger::{BodyFallback, DuplicateKeys, EventDestination, SpanFormat};
+#[cfg(feature = "metrics")]
+pub use guard::MetricsGuard;
+#[cfg(all(feature = "traces", feature = "logs"))]
+pub use guard::OtelGuard;
+pub use links::add_span_link;
+#[cfg(feature = "traces")]
+pub use processor::{ErrorTraceProcessor, LatencyThresholdProcessor};
 
+#[cfg(feature = "traces")]
 /// Creates a tracing layer that can be added to a `tracing_subscriber`like this
 ///
-/// ```
+/// ```no_run
+/// # use axum_otlp_honeycomb::try_init_otlp_layer;
+/// # use tracing_subscriber::{filter::LevelFilter, prelude::*};
+/// # fn main() -> Result<(), axum_otlp_honeycomb::InitError> {
 /// let sample_rate = 0.01;  // 1%
 /// tracing_subscriber::Registry::default()
-///    .with(init_otlp_layer(sample_rate).with_filter(LevelFilter::INFO))
+///    .with(try_init_otlp_layer(sample_rate)?.with_filter(LevelFilter::INFO))
 ///    .init();
+/// # Ok(())
+/// # }
 /// ```
 ///
 /// The `sample_rate` is the fraction of traces that should be sent to Honeycomb.
-/// 1.0 is all traces.
+/// 1.0 is all traces. It is clamped to 0.0..=1.0, and the environment variable
+/// `OTEL_TRACES_SAMPLER_ARG` overrides it, so it can be tuned per environment
+/// without recompiling.
 ///
 /// Also sets a `text_map_propagator` to enable propagation
-/// of context between services.
+/// of context between services, unless `OtlpConfigBuilder::skip_global_propagator()`
+/// is used with `init_otlp_layer_with_config()`.
 ///
 /// Expects the following environment variables:
-/// *  `HONEYCOMB_API_KEY` contains
-///    the API key for the Honeycomb environment that traces should be sent to
 /// *  `OTEL_EXPORTER_OTLP_ENDPOINT` contains the endpoint for Honeycomb -
 ///    eg `https://api.eu1.honeycomb.io/`
+/// *  `OTEL_EXPORTER_OTLP_HEADERS` contains the headers for Honeycomb -
+///    eg `x-honeycomb-team=<the Honeycomb Ingress API key>`
 /// *  `OTEL_SERVICE_NAME` contains the service name - eg `clap::crate_name!()`.
+///
+/// # Errors
+///
+/// Returns `InitError::MissingEnv` if the endpoint or service name variable
+/// is not set, and `InitError::Exporter` if the exporter could not be built.
+pub fn try_init_otlp_layer<S>(sample_rate: f64) -> Result<OpenTelemetryLayer<S, Tracer>, InitError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    init_otlp_layer_with_config(
+        &OtlpConfig::builder()
+            .default_sample_rate(sample_rate)
+            .build(),
+    )
+}
+
+#[cfg(feature = "traces")]
+/// Creates a tracing layer like `try_init_otlp_layer()`, but returns `None`
+/// on errors.
+#[deprecated(note = "use `try_init_otlp_layer`, which tells why the layer could not be created")]
 pub fn init_otlp_layer<S>(sample_rate: f64) -> Option<OpenTelemetryLayer<S, Tracer>>
 where
     S: Subscriber + for<'span> LookupSpan<'span>,
 {
-    opentelemetry::global::set_text_map_propagator(
-        opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+    try_init_otlp_layer(sample_rate).ok()
+}
+
+#[cfg(feature = "traces")]
+/// Creates a tracing layer like `try_init_otlp_layer()`, that only exports the
+/// spans that took at least `threshold`.
+///
+/// All (sampled) spans are recorded, but the fast ones are dropped before
+/// export. This is a tail sampling on latency, for hunting p99 outliers in
+/// Honeycomb without paying for the fast requests. Each span is judged
+/// on its own, so fast child spans of a slow request are not exported.
+///
+/// # Errors
+///
+/// As for `try_init_otlp_layer()`.
+pub fn init_otlp_layer_with_latency_threshold<S>(
+    sample_rate: f64,
+    threshold: Duration,
+) -> Result<OpenTelemetryLayer<S, Tracer>, InitError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    init_otlp_layer_with_config(
+        &OtlpConfig::builder()
+            .default_sample_rate(sample_rate)
+            .latency_threshold(threshold)
+            .build(),
+    )
+}
+
+#[cfg(feature = "traces")]
+/// Creates a tracing layer like `try_init_otlp_layer()`, configured by `config`
+/// instead of environment variables.
+///
+/// ```no_run
+/// # use axum_otlp_honeycomb::{OtlpConfig, init_otlp_layer_with_config};
+/// # use tracing_subscriber::{filter::LevelFilter, prelude::*};
+/// # fn main() -> Result<(), axum_otlp_honeycomb::InitError> {
+/// let config = OtlpConfig::builder()
+///     .api_key("<the Honeycomb Ingress API key>")
+///     .endpoint("https://api.eu1.honeycomb.io")
+///     .service_name("my-service")
+///     .build();
+/// tracing_subscriber::Registry::default()
+///    .with(init_otlp_layer_with_config(&config)?.with_filter(LevelFilter::INFO))
+///    .init();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// Returns `InitError::MissingEnv` if the endpoint or service name is neither
+/// in `config` nor in the environment, and `InitError::Exporter` if the
+/// exporter could not be built.
+pub fn init_otlp_layer_with_config<S>(
+    config: &OtlpConfig,
+) -> Result<OpenTelemetryLayer<S, Tracer>, InitError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    Ok(tracing_layer(&tracer_provider(config)?.0, config))
+}
+
+#[cfg(feature = "traces")]
+/// Creates a tracing layer like `init_otlp_layer_with_config()`, and a handle
+/// to change the sample rate while the program runs
+///
+/// ```no_run
+/// # use axum_otlp_honeycomb::{OtlpConfig, init_otlp_layer_with_handle};
+/// # use tracing_subscriber::{filter::LevelFilter, prelude::*};
+/// # fn main() -> Result<(), axum_otlp_honeycomb::InitError> {
+/// let (layer, sample_rate) =
+///     init_otlp_layer_with_handle(&OtlpConfig::builder().sample_rate(0.01).build())?;
+/// tracing_subscriber::Registry::default()
+///    .with(layer.with_filter(LevelFilter::INFO))
+///    .init();
+/// // keep all traces during an incident
+/// sample_rate.set_rate(1.0);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// As for `init_otlp_layer_with_config()`.
+pub fn init_otlp_layer_with_handle<S>(
+    config: &OtlpConfig,
+) -> Result<(OpenTelemetryLayer<S, Tracer>, SampleRateHandle), InitError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let (tracer_provider, sample_rate) = tracer_provider(config)?;
+    Ok((tracing_layer(&tracer_provider, config), sample_rate))
+}
+
+#[cfg(all(feature = "traces", feature = "logs"))]
+/// Creates both layers and an [`OtelGuard`] for the providers behind them
+///
+/// ```no_run
+/// # use axum_otlp_honeycomb::{OtlpConfig, init};
+/// # use tracing_subscriber::{filter::LevelFilter, prelude::*};
+/// # fn main() -> Result<(), axum_otlp_honeycomb::InitError> {
+/// let (layer, log_layer, guard) = init(&OtlpConfig::builder().sample_rate(0.01).build())?;
+/// tracing_subscriber::Registry::default()
+///    .with(layer.with_filter(LevelFilter::INFO))
+///    .with(log_layer.with_filter(LevelFilter::INFO))
+///    .init();
+/// // ...
+/// guard.flush();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// As for `init_otlp_layer_with_config()`.
+#[allow(clippy::type_complexity)]
+pub fn init<S>(
+    config: &OtlpConfig,
+) -> Result<
+    (
+        OpenTelemetryLayer<S, Tracer>,
+        AxumOtelEventLogger<SdkLoggerProvider, SdkLogger>,
+        OtelGuard,
+    ),
+    InitError,
+>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let (tracer_provider, sample_rate) = tracer_provider(config)?;
+    let logger_provider = logger_provider(config)?;
+    Ok((
+        tracing_layer(&tracer_provider, config),
+        log_layer(&logger_provider, config),
+        OtelGuard::new(tracer_provider, logger_provider, sample_rate),
+    ))
+}
+
+#[cfg(feature = "traces")]
+fn tracer_provider(
+    config: &OtlpConfig,
+) -> Result<(SdkTracerProvider, SampleRateHandle), InitError> {
+    let exporter = config.traces_exporter();
+    if exporter == TracesExporter::Otlp {
+        if config.endpoint().is_none() {
+            require_env(&[
+                "OTEL_EXPORTER_OTLP_ENDPOINT",
+                "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+            ])?;
+        }
+        if config.service_name.is_none() {
+            require_env(&["OTEL_SERVICE_NAME"])?;
+        }
+    }
+
+    if config.install_propagators {
+        install_propagators(config);
+    }
+
+    let choice = |choice: SamplerChoice| match choice {
+        // the rate is applied by the ErrorTraceProcessor when the status is known
+        SamplerChoice::Ratio if config.keep_error_traces => SamplerChoice::AlwaysOn,
+        choice => choice,
+    };
+    let root_choice = match config.root_sampler {
+        // the same as `Ratio` without a parent
+        SamplerChoice::FollowParent => choice(SamplerChoice::Ratio),
+        root => choice(root),
+    };
+    let sample_rate = SampleRateHandle::new(config.sample_rate());
+    let sampler = ParentBasedSampler::new(
+        root_choice,
+        choice(config.remote_parent_sampler),
+        choice(config.local_parent_sampler),
+        sample_rate.clone(),
     );
+    let mut builder = SdkTracerProvider::builder().with_sampler(sampler);
+    if let Some(resource) = resource(config) {
+        builder = builder.with_resource(resource);
+    }
+    let processors = match exporter {
+        TracesExporter::Otlp => (0..config.max_concurrent_exports())
+            .map(|_| {
+                Ok(BatchSpanProcessor::builder(span_exporter(config)?)
+                    .with_batch_config(span_batch_config(config))
+                    .build())
+            })
+            .collect::<Result<_, InitError>>()?,
+        TracesExporter::Console => vec![
+            BatchSpanProcessor::builder(opentelemetry_stdout::SpanExporter::default())
+                .with_batch_config(span_batch_config(config))
+                .build(),
+        ],
+        // the spans are still created, so the context is propagated
+        TracesExporter::None => return Ok((builder.build(), sample_rate)),
+    };
+    let processor = FanoutProcessor::new(processors);
+    // The ErrorTraceProcessor must see all spans, so it is the outer processor
+    let builder = match (config.keep_error_traces, config.latency_threshold) {
+        (false, None) => builder.with_span_processor(processor),
+        (false, Some(threshold)) => {
+            builder.with_span_processor(LatencyThresholdProcessor::new(processor, threshold))
+        }
+        (true, None) => builder.with_span_processor(
+            ErrorTraceProcessor::with_sample_rate_handle(processor, sample_rate.clone())
+                .with_sampler_choices(config.root_sampler, config.remote_parent_sampler),
+        ),
+        (true, Some(threshold)) => builder.with_span_processor(
+            ErrorTraceProcessor::with_sample_rate_handle(
+                LatencyThresholdProcessor::new(processor, threshold),
+                sample_rate.clone(),
+            )
+            .with_sampler_choices(config.root_sampler, config.remote_parent_sampler),
+        ),
+    };
+    Ok((builder.build(), sample_rate))
+}
+
+/// Install the propagators of `config` as the global text map propagator
+///
+/// The tracing layer does this when it is created, unless
+/// `OtlpConfigBuilder::skip_global_propagator()` is used. This replaces any
+/// global propagator set before.
+pub fn install_propagators(config: &OtlpConfig) {
+    let propagators = config
+        .propagators
+        .iter()
+        .map(|propagator| -> Box<dyn TextMapPropagator + Send + Sync> {
+            match propagator {
+                Propagator::TraceContext => Box::new(TraceContextPropagator::new()),
+                Propagator::Baggage => Box::new(BaggagePropagator::new()),
+            }
+        })
+        .collect();
+    opentelemetry::global::set_text_map_propagator(TextMapCompositePropagator::new(propagators));
+}
 
-    if let Ok(exporter) = SpanExporter::builder().with_http().build() {
-        let provider = sdk::trace::SdkTracerProvider::builder()
-            .with_batch_exporter(exporter)
-            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
-                sample_rate,
-            ))))
-            .build();
-        let tracer = provider.tracer("axum-otlp-honeycomb");
-        let layer = tracing_opentelemetry::layer()
-            .with_level(true)
-            .with_tracer(tracer);
-        Some(layer)
-    } else {
-        None
+#[cfg(feature = "traces")]
+fn tracing_layer<S>(
+    provider: &SdkTracerProvider,
+    config: &OtlpConfig,
+) -> OpenTelemetryLayer<S, Tracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let name = config
+        .scope_name
+        .clone()
+        .unwrap_or_else(|| "axum-otlp-honeycomb".to_string());
+    let mut scope = InstrumentationScope::builder(name);
+    if let Some(version) = &config.scope_version {
+        scope = scope.with_version(version.clone());
     }
+    let tracer = provider.tracer_with_scope(scope.build());
+    tracing_opentelemetry::layer()
+        .with_level(true)
+        .with_tracer(tracer)
 }
 
+#[cfg(feature = "logs")]
 /// Creates an event logging layer that can be added to a `tracing_subscriber`like this
 ///
-/// ```
+/// ```no_run
+/// # use axum_otlp_honeycomb::init_otlp_log_layer;
+/// # use tracing_subscriber::{filter::LevelFilter, prelude::*};
+/// # fn main() -> Result<(), axum_otlp_honeycomb::InitError> {
 /// tracing_subscriber::Registry::default()
-///    .with(init_otlp_log_ layer().with_filter(LevelFilter::INFO))
+///    .with(init_otlp_log_layer()?.with_filter(LevelFilter::INFO))
 ///    .init();
+/// # Ok(())
+/// # }
 /// ```
 ///
 /// This layer sends events (with level greater than or equal to INFO) onwards
@@ -77,11 +429,278 @@ where
 /// to be in the field `message`.  In `opentelemetry` this is moved to the `body`
 /// field. Any field in the event with the name `body` will overwrite the event message.
 ///
-/// Expects the same environment variables as `init_otlp_log_layer()`
-pub fn init_otlp_log_layer() -> AxumOtelEventLogger<SdkLoggerProvider, SdkLogger> {
-    let exporter = LogExporter::builder().with_http().build().unwrap();
-    let provider = sdk::logs::SdkLoggerProvider::builder()
-        .with_batch_exporter(exporter)
+/// To make sure the last errors before a crash are exported, use
+/// `.with_flush_level(Level::ERROR)` on the layer to flush after each error.
+///
+/// Expects the same environment variables as `try_init_otlp_layer()`
+///
+/// # Errors
+///
+/// Returns `InitError::MissingEnv` if the endpoint or service name variable
+/// is not set, and `InitError::Exporter` if the exporter could not be built.
+pub fn init_otlp_log_layer() -> Result<AxumOtelEventLogger<SdkLoggerProvider, SdkLogger>, InitError>
+{
+    init_otlp_log_layer_with_config(&OtlpConfig::default())
+}
+
+#[cfg(feature = "logs")]
+/// Creates an event logging layer like `init_otlp_log_layer()`, configured by
+/// `config` instead of environment variables.
+///
+/// # Errors
+///
+/// As for `init_otlp_layer_with_config()`.
+pub fn init_otlp_log_layer_with_config(
+    config: &OtlpConfig,
+) -> Result<AxumOtelEventLogger<SdkLoggerProvider, SdkLogger>, InitError> {
+    Ok(log_layer(&logger_provider(config)?, config))
+}
+
+#[cfg(feature = "logs")]
+fn logger_provider(config: &OtlpConfig) -> Result<SdkLoggerProvider, InitError> {
+    if config.endpoint().is_none() {
+        require_env(&[
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            "OTEL_EXPORTER_OTLP_LOGS_ENDPOINT",
+        ])?;
+    }
+    if config.service_name.is_none() {
+        require_env(&["OTEL_SERVICE_NAME"])?;
+    }
+
+    let exporter = log_exporter(config)?;
+    let processor = BatchLogProcessor::builder(exporter)
+        .with_batch_config(log_batch_config(config))
         .build();
-    AxumOtelEventLogger::new(&provider)
+    let mut builder = SdkLoggerProvider::builder().with_log_processor(processor);
+    if let Some(resource) = resource(config) {
+        builder = builder.with_resource(resource);
+    }
+    Ok(builder.build())
+}
+
+#[cfg(feature = "logs")]
+fn log_layer(
+    provider: &SdkLoggerProvider,
+    config: &OtlpConfig,
+) -> AxumOtelEventLogger<SdkLoggerProvider, SdkLogger> {
+    let flush_provider = provider.clone();
+    AxumOtelEventLogger::with_scope(
+        provider,
+        config.scope_name.clone(),
+        config.scope_version.clone(),
+    )
+    .with_flusher(move || {
+        let _ = flush_provider.force_flush();
+    })
+}
+
+#[cfg(feature = "metrics")]
+/// Creates a meter provider exporting metrics to Honeycomb, and sets it as the
+/// global meter provider.
+///
+/// ```no_run
+/// # use axum_otlp_honeycomb::{init_otlp_metrics, opentelemetry_tracing_layer};
+/// # fn main() -> Result<(), axum_otlp_honeycomb::InitError> {
+/// let _metrics = init_otlp_metrics()?;
+/// let layer = opentelemetry_tracing_layer().with_metrics();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The metrics are exported every minute, or as set by `OTEL_METRIC_EXPORT_INTERVAL`.
+/// Honeycomb stores metrics in the dataset given by the `x-honeycomb-dataset`
+/// header, eg. in `OTEL_EXPORTER_OTLP_METRICS_HEADERS`.
+///
+/// The returned guard flushes and shuts down the provider when it is dropped,
+/// so keep it in `main`.
+///
+/// Expects the same environment variables as `try_init_otlp_layer()`
+///
+/// # Errors
+///
+/// As for `try_init_otlp_layer()`.
+pub fn init_otlp_metrics() -> Result<MetricsGuard, InitError> {
+    init_otlp_metrics_with_config(&OtlpConfig::default())
+}
+
+#[cfg(feature = "metrics")]
+/// Creates a meter provider like `init_otlp_metrics()`, configured by `config`
+/// instead of environment variables.
+///
+/// # Errors
+///
+/// As for `init_otlp_layer_with_config()`.
+pub fn init_otlp_metrics_with_config(config: &OtlpConfig) -> Result<MetricsGuard, InitError> {
+    if config.endpoint().is_none() {
+        require_env(&[
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            "OTEL_EXPORTER_OTLP_METRICS_ENDPOINT",
+        ])?;
+    }
+    if config.service_name.is_none() {
+        require_env(&["OTEL_SERVICE_NAME"])?;
+    }
+
+    let exporter = metric_exporter(config)?;
+    let mut builder = SdkMeterProvider::builder().with_periodic_exporter(exporter);
+    if let Some(resource) = resource(config) {
+        builder = builder.with_resource(resource);
+    }
+    let provider = builder.build();
+    opentelemetry::global::set_meter_provider(provider.clone());
+    Ok(MetricsGuard::new(provider))
+}
+
+#[cfg(any(feature = "traces", feature = "logs", feature = "metrics"))]
+/// Build an OTLP exporter of type `$exporter` for `$config`, sending HTTP to
+/// the endpoint with `$path` appended
+macro_rules! build_exporter {
+    ($exporter:ty, $config:expr, $path:literal) => {{
+        let config: &OtlpConfig = $config;
+        let endpoint = config.signal_endpoint($path);
+        match config.protocol {
+            Protocol::HttpProtobuf => {
+                let mut builder = <$exporter>::builder()
+                    .with_http()
+                    .with_headers(honeycomb_headers(config));
+                if config.compression == Compression::Gzip {
+                    builder = builder.with_compression(opentelemetry_otlp::Compression::Gzip);
+                }
+                if let Some(timeout) = config.timeout {
+                    builder = builder.with_timeout(timeout);
+                }
+                if let Some(client) = tls::http_client(config)? {
+                    builder = builder.with_http_client(client);
+                }
+                match endpoint {
+                    Some(endpoint) => builder.with_endpoint(endpoint).build(),
+                    None => builder.build(),
+                }
+            }
+            Protocol::Grpc => {
+                let mut builder = <$exporter>::builder()
+                    .with_tonic()
+                    .with_metadata(honeycomb_metadata(config));
+                if let Some(tls) = tls::tonic_tls_config(config)? {
+                    builder = builder.with_tls_config(tls);
+                }
+                if config.compression == Compression::Gzip {
+                    builder = builder.with_compression(opentelemetry_otlp::Compression::Gzip);
+                }
+                if let Some(timeout) = config.timeout {
+                    builder = builder.with_timeout(timeout);
+                }
+                match endpoint {
+                    Some(endpoint) => builder.with_endpoint(endpoint).build(),
+                    None => builder.build(),
+                }
+            }
+        }
+    }};
+}
+
+#[cfg(feature = "traces")]
+/// The batch config from the environment, with the values set in `config`
+fn span_batch_config(config: &OtlpConfig) -> trace::BatchConfig {
+    let mut builder = trace::BatchConfigBuilder::default();
+    if let Some(size) = config.max_queue_size {
+        builder = builder.with_max_queue_size(size);
+    }
+    if let Some(delay) = config.scheduled_delay {
+        builder = builder.with_scheduled_delay(delay);
+    }
+    if let Some(size) = config.max_export_batch_size {
+        builder = builder.with_max_export_batch_size(size);
+    }
+    builder.build()
+}
+
+#[cfg(feature = "logs")]
+/// The batch config from the environment, with the values set in `config`
+fn log_batch_config(config: &OtlpConfig) -> logs::BatchConfig {
+    let mut builder = logs::BatchConfigBuilder::default();
+    if let Some(size) = config.max_queue_size {
+        builder = builder.with_max_queue_size(size);
+    }
+    if let Some(delay) = config.scheduled_delay {
+        builder = builder.with_scheduled_delay(delay);
+    }
+    if let Some(size) = config.max_export_batch_size {
+        builder = builder.with_max_export_batch_size(size);
+    }
+    builder.build()
+}
+
+#[cfg(feature = "traces")]
+fn span_exporter(config: &OtlpConfig) -> Result<SpanExporter, InitError> {
+    Ok(build_exporter!(SpanExporter, config, "/v1/traces")?)
+}
+
+#[cfg(feature = "logs")]
+fn log_exporter(config: &OtlpConfig) -> Result<LogExporter, InitError> {
+    Ok(build_exporter!(LogExporter, config, "/v1/logs")?)
+}
+
+#[cfg(feature = "metrics")]
+fn metric_exporter(config: &OtlpConfig) -> Result<MetricExporter, InitError> {
+    Ok(build_exporter!(MetricExporter, config, "/v1/metrics")?)
+}
+
+#[cfg(any(feature = "traces", feature = "logs", feature = "metrics"))]
+/// The `x-honeycomb-team` header, if the API key is configured
+///
+/// The exporter adds the headers of `OTEL_EXPORTER_OTLP_HEADERS` and the
+/// signal-specific `OTEL_EXPORTER_OTLP_<SIGNAL>_HEADERS`, which win.
+fn honeycomb_headers(config: &OtlpConfig) -> HashMap<String, String> {
+    config
+        .api_key
+        .iter()
+        .map(|key| ("x-honeycomb-team".to_string(), key.clone()))
+        .collect()
+}
+
+#[cfg(any(feature = "traces", feature = "logs", feature = "metrics"))]
+/// The `x-honeycomb-team` header as gRPC metadata, see `honeycomb_headers()`
+///
+/// An API key that is not a valid header value is left out, and the export is
+/// then rejected by Honeycomb.
+fn honeycomb_metadata(config: &OtlpConfig) -> MetadataMap {
+    let mut headers = http::HeaderMap::new();
+    if let Some(value) = config
+        .api_key
+        .as_deref()
+        .and_then(|key| http::HeaderValue::try_from(key).ok())
+    {
+        headers.insert("x-honeycomb-team", value);
+    }
+    MetadataMap::from_headers(headers)
+}
+
+#[cfg(any(feature = "traces", feature = "logs", feature = "metrics"))]
+/// The resource with the service name and attributes, if they are configured
+fn resource(config: &OtlpConfig) -> Option<Resource> {
+    if config.service_name.is_none()
+        && config.resource_attributes.is_empty()
+        && config.deployment_color.is_none()
+        && config.canary.is_none()
+    {
+        return None;
+    }
+    let mut builder = Resource::builder();
+    if let Some(name) = &config.service_name {
+        builder = builder.with_service_name(name.clone());
+    }
+    let mut attributes: Vec<KeyValue> = config
+        .resource_attributes
+        .iter()
+        .map(|(key, value)| KeyValue::new(key.clone(), value.clone()))
+        .collect();
+    if let Some(color) = &config.deployment_color {
+        attributes.push(KeyValue::new("deployment.color", color.clone()));
+    }
+    if let Some(canary) = config.canary {
+        attributes.push(KeyValue::new("deployment.canary", canary));
+    }
+    Some(builder.with_attributes(attributes).build())
 }
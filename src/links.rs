@@ -0,0 +1,37 @@
+//! Links between traces
+
+use opentelemetry::trace::{SpanContext, SpanId, TraceFlags, TraceId, TraceState};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Add a link from the current span to the span `span_id` in the trace `trace_id`
+///
+/// In a handler the current span is the request span, unless the code runs in
+/// a span of its own, eg. from `#[tracing::instrument]`. Honeycomb shows the
+/// link on the span, and following it opens the other trace. Invalid ids are
+/// ignored.
+///
+/// A request that enqueues a background job can save its own ids in the job
+/// payload, for the job to link back to the request:
+/// ```
+/// use opentelemetry::trace::TraceContextExt;
+/// use tracing_opentelemetry::OpenTelemetrySpanExt;
+///
+/// // in the handler
+/// let context = tracing::Span::current().context();
+/// let request_span = context.span().span_context().clone();
+/// let (trace_id, span_id) = (request_span.trace_id(), request_span.span_id());
+/// // ... store them with the job, and when processing it
+/// axum_otlp_honeycomb::add_span_link(trace_id, span_id);
+/// ```
+/// When the job system gives the job's trace and span id at once, the request
+/// span can link to the job in the same way.
+pub fn add_span_link(trace_id: TraceId, span_id: SpanId) {
+    let context = SpanContext::new(
+        trace_id,
+        span_id,
+        TraceFlags::SAMPLED,
+        true,
+        TraceState::default(),
+    );
+    tracing::Span::current().add_link(context);
+}
@@ -0,0 +1,165 @@
+//! HTTP server metrics recorded by the layer
+
+use opentelemetry::{
+    KeyValue,
+    metrics::{Counter, Histogram, UpDownCounter},
+};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+/// The `http.route` of requests without a matched route, eg. 404s. Using the
+/// path would give the metrics an unbounded number of routes.
+pub(crate) const UNMATCHED_ROUTE: &str = "<unmatched>";
+
+/// The bucket boundaries of `http.server.request.duration` recommended by the
+/// semantic conventions, in seconds. The SDK default is meant for milliseconds.
+const DURATION_BOUNDARIES: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5, 5.0, 7.5, 10.0,
+];
+
+/// The request count, duration, body sizes and active requests of the server.
+///
+/// The instruments are created from the global meter provider on first use,
+/// so `init_otlp_metrics()` can be called after the layer is created.
+#[derive(Debug, Default)]
+pub(crate) struct HttpMetrics {
+    instruments: OnceLock<Instruments>,
+}
+
+#[derive(Debug)]
+struct Instruments {
+    requests: Counter<u64>,
+    duration: Histogram<f64>,
+    active: UpDownCounter<i64>,
+    request_body_size: Histogram<u64>,
+    response_body_size: Histogram<u64>,
+}
+
+impl HttpMetrics {
+    fn instruments(&self) -> &Instruments {
+        self.instruments.get_or_init(|| {
+            let meter = opentelemetry::global::meter("axum-otlp-honeycomb");
+            Instruments {
+                requests: meter
+                    .u64_counter("http.server.request.count")
+                    .with_description("Number of HTTP server requests")
+                    .build(),
+                duration: meter
+                    .f64_histogram("http.server.request.duration")
+                    .with_description("Duration of HTTP server requests")
+                    .with_unit("s")
+                    .with_boundaries(DURATION_BOUNDARIES.to_vec())
+                    .build(),
+                active: meter
+                    .i64_up_down_counter("http.server.active_requests")
+                    .with_description("Number of active HTTP server requests")
+                    .with_unit("{request}")
+                    .build(),
+                request_body_size: meter
+                    .u64_histogram("http.server.request.body.size")
+                    .with_description("Size of HTTP server request bodies")
+                    .with_unit("By")
+                    .build(),
+                response_body_size: meter
+                    .u64_histogram("http.server.response.body.size")
+                    .with_description("Size of HTTP server response bodies")
+                    .with_unit("By")
+                    .build(),
+            }
+        })
+    }
+
+    /// Count the request as active until the returned value is dropped.
+    pub(crate) fn start(self: &Arc<Self>, method: &http::Method, route: &str) -> ActiveRequest {
+        let attributes = vec![
+            KeyValue::new("http.request.method", method_label(method)),
+            KeyValue::new("http.route", route_label(route)),
+        ];
+        self.instruments().active.add(1, &attributes);
+        ActiveRequest {
+            metrics: Arc::clone(self),
+            attributes,
+        }
+    }
+
+    /// Count the request and record its duration and body sizes, `status` is
+    /// `None` when the service failed without a response.
+    ///
+    /// The body sizes are only recorded when they are known from `Content-Length`.
+    pub(crate) fn record(
+        &self,
+        method: &http::Method,
+        route: &str,
+        status: Option<u16>,
+        elapsed: Duration,
+        body_sizes: (Option<u64>, Option<u64>),
+    ) {
+        let instruments = self.instruments();
+        let mut attributes = vec![
+            KeyValue::new("http.request.method", method_label(method)),
+            KeyValue::new("http.route", route_label(route)),
+        ];
+        match status {
+            Some(status) => {
+                attributes.push(KeyValue::new(
+                    "http.response.status_code",
+                    i64::from(status),
+                ));
+            }
+            None => attributes.push(KeyValue::new("error.type", "transport")),
+        }
+        instruments.requests.add(1, &attributes);
+        instruments
+            .duration
+            .record(elapsed.as_secs_f64(), &attributes);
+        let (request_size, response_size) = body_sizes;
+        if let Some(size) = request_size {
+            instruments.request_body_size.record(size, &attributes);
+        }
+        if let Some(size) = response_size {
+            instruments.response_body_size.record(size, &attributes);
+        }
+    }
+}
+
+/// A request counted in `http.server.active_requests`, also when the request
+/// future is dropped before it completes.
+#[derive(Debug)]
+pub(crate) struct ActiveRequest {
+    metrics: Arc<HttpMetrics>,
+    attributes: Vec<KeyValue>,
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.metrics.instruments().active.add(-1, &self.attributes);
+    }
+}
+
+/// The route, or `<unmatched>` for requests without a matched route
+fn route_label(route: &str) -> String {
+    if route.is_empty() {
+        UNMATCHED_ROUTE.to_string()
+    } else {
+        route.to_string()
+    }
+}
+
+/// The method, or `_OTHER` for methods that are not in the HTTP RFCs, which
+/// clients can make up freely
+fn method_label(method: &http::Method) -> &'static str {
+    match *method {
+        http::Method::CONNECT => "CONNECT",
+        http::Method::DELETE => "DELETE",
+        http::Method::GET => "GET",
+        http::Method::HEAD => "HEAD",
+        http::Method::OPTIONS => "OPTIONS",
+        http::Method::PATCH => "PATCH",
+        http::Method::POST => "POST",
+        http::Method::PUT => "PUT",
+        http::Method::TRACE => "TRACE",
+        _ => "_OTHER",
+    }
+}
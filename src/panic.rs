@@ -0,0 +1,45 @@
+//! Panics in the handlers
+
+use std::{any::Any, cell::RefCell, sync::Once};
+
+thread_local! {
+    /// The location of the last panic on this thread, set by the panic hook
+    static LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Install a panic hook that remembers the location of the panic, before
+/// calling the hook that was installed before it.
+///
+/// The hook is only installed once, also when called from several layers.
+pub(crate) fn install_location_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let location = info.location().map(|location| {
+                format!(
+                    "{}:{}:{}",
+                    location.file(),
+                    location.line(),
+                    location.column()
+                )
+            });
+            LOCATION.with(|last| *last.borrow_mut() = location);
+            previous(info);
+        }));
+    });
+}
+
+/// The location of the panic that is being caught, if the hook is installed
+pub(crate) fn take_location() -> Option<String> {
+    LOCATION.with(|last| last.borrow_mut().take())
+}
+
+/// The message of a panic from `panic!()` or `unwrap()`
+pub(crate) fn message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| (*message).to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".to_string())
+}
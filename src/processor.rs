@@ -0,0 +1,568 @@
+//! Span processors
+
+use crate::{config::SamplerChoice, sampler::SampleRateHandle};
+use opentelemetry::{
+    Context,
+    trace::{Span as _, SpanId, Status, TraceContextExt, TraceId},
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    trace::{Span, SpanData, SpanProcessor},
+};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Span processor that only passes the spans that took at least
+/// `threshold` on to the inner processor.
+///
+/// All spans are still recorded, but only the slow ones are exported, which
+/// is useful for hunting latency outliers without paying for the fast requests.
+///
+/// Each span is judged on its own duration, so a slow request span is
+/// exported without the fast child spans under it.
+#[derive(Debug)]
+pub struct LatencyThresholdProcessor<P> {
+    inner: P,
+    threshold: Duration,
+}
+
+impl<P: SpanProcessor> LatencyThresholdProcessor<P> {
+    pub fn new(inner: P, threshold: Duration) -> Self {
+        LatencyThresholdProcessor { inner, threshold }
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for LatencyThresholdProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        let duration = span
+            .end_time
+            .duration_since(span.start_time)
+            .unwrap_or_default();
+        if duration >= self.threshold {
+            self.inner.on_end(span);
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// Span processor that hands each trace to one of the inner processors, so
+/// their batches are exported at the same time
+///
+/// The trace id picks the processor, so the spans of a trace stay together.
+#[derive(Debug)]
+pub(crate) struct FanoutProcessor<P> {
+    inner: Vec<P>,
+}
+
+impl<P: SpanProcessor> FanoutProcessor<P> {
+    /// `inner` must not be empty
+    pub(crate) fn new(inner: Vec<P>) -> Self {
+        debug_assert!(!inner.is_empty());
+        FanoutProcessor { inner }
+    }
+
+    fn processor(&self, trace_id: TraceId) -> &P {
+        let index = u128::from_be_bytes(trace_id.to_bytes()) % self.inner.len() as u128;
+        &self.inner[index as usize]
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for FanoutProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        let trace_id = span.span_context().trace_id();
+        self.processor(trace_id).on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        self.processor(span.span_context.trace_id()).on_end(span);
+    }
+
+    /// Flushes all processors, and returns the first error
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner
+            .iter()
+            .map(SpanProcessor::force_flush)
+            .fold(Ok(()), OTelSdkResult::and)
+    }
+
+    /// Shuts down all processors within `timeout` together, and returns the first error
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        let deadline = Instant::now() + timeout;
+        self.inner
+            .iter()
+            .map(|processor| {
+                processor.shutdown_with_timeout(deadline.saturating_duration_since(Instant::now()))
+            })
+            .fold(Ok(()), OTelSdkResult::and)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        for processor in &mut self.inner {
+            processor.set_resource(resource);
+        }
+    }
+}
+
+/// The most spans held back for one trace, later spans of the trace are dropped
+const MAX_BUFFERED_SPANS: usize = 1024;
+
+/// The most traces kept track of, the traces that have ended or are older than
+/// `MAX_TRACE_AGE` make room for new ones first, then the oldest trace
+const MAX_PENDING_TRACES: usize = 4096;
+
+/// The age after which a trace whose local root span has not ended can be
+/// forgotten
+const MAX_TRACE_AGE: Duration = Duration::from_secs(600);
+
+/// Span processor that passes `sample_rate` of the traces on to the inner
+/// processor, and all traces whose local root span ends in error.
+///
+/// The tracer provider must sample all traces (eg. `Sampler::AlwaysOn`) for
+/// this to work, as the status is not known when the sampler runs. The spans
+/// of traces outside the rate are held back until their local root span ends,
+/// and are only exported when it has the status `Error`. Spans that end after
+/// the root span are only exported when they have the status `Error` themselves.
+/// The trace id decides
+/// the rate the same way as `Sampler::TraceIdRatioBased`.
+///
+/// The rate only applies to root spans. Spans with a parent from another
+/// service follow the decision of the caller, so its traces are not cut short
+/// here, unless `with_sampler_choices()` says otherwise.
+///
+/// All spans are recorded, and the spans of a trace are kept in memory until it
+/// ends, for at most 4096 traces of 1024 spans. A trace whose root span never
+/// ends is forgotten when room is needed. This costs more CPU and memory than
+/// head sampling. And with many errors many more traces than `sample_rate` are
+/// sent to Honeycomb.
+#[derive(Debug)]
+pub struct ErrorTraceProcessor<P> {
+    inner: P,
+    sample_rate: SampleRateHandle,
+    /// the rate decides for spans without a parent
+    rate_for_roots: bool,
+    /// the rate decides for spans with a remote parent
+    rate_for_remote_parents: bool,
+    pending: Mutex<HashMap<TraceId, PendingTrace>>,
+}
+
+/// The spans of a trace outside the rate
+#[derive(Debug)]
+struct PendingTrace {
+    /// held back until a local root span ends
+    spans: Vec<SpanData>,
+    /// the local root spans of the trace that have not ended, eg. of two
+    /// requests in the same trace of the caller
+    roots: usize,
+    state: PendingState,
+    started: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PendingState {
+    /// the spans are held back
+    Holding,
+    /// a local root span has failed, the spans are passed on
+    Kept,
+    /// the local root spans have ended without error, only failing spans are
+    /// passed on
+    Dropped,
+}
+
+impl PendingTrace {
+    fn new() -> Self {
+        PendingTrace {
+            spans: Vec::new(),
+            roots: 0,
+            state: PendingState::Holding,
+            started: Instant::now(),
+        }
+    }
+}
+
+/// Make room for a new trace in `pending`
+fn evict(pending: &mut HashMap<TraceId, PendingTrace>) {
+    if pending.len() < MAX_PENDING_TRACES {
+        return;
+    }
+    pending.retain(|_, trace| {
+        trace.state == PendingState::Holding && trace.started.elapsed() < MAX_TRACE_AGE
+    });
+    if pending.len() >= MAX_PENDING_TRACES
+        && let Some(oldest) = pending
+            .iter()
+            .min_by_key(|(_, trace)| trace.started)
+            .map(|(trace_id, _)| *trace_id)
+    {
+        pending.remove(&oldest);
+    }
+}
+
+impl<P: SpanProcessor> ErrorTraceProcessor<P> {
+    pub fn new(inner: P, sample_rate: f64) -> Self {
+        Self::with_sample_rate_handle(inner, SampleRateHandle::new(sample_rate))
+    }
+
+    /// Create the processor with the rate of `sample_rate`, which can be
+    /// changed while it runs
+    pub fn with_sample_rate_handle(inner: P, sample_rate: SampleRateHandle) -> Self {
+        ErrorTraceProcessor {
+            inner,
+            sample_rate,
+            rate_for_roots: true,
+            rate_for_remote_parents: false,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Apply the rate to the spans the samplers `root` and `remote_parent` of
+    /// `OtlpConfigBuilder` apply it to, the default is `SamplerChoice::Ratio`
+    /// and `SamplerChoice::FollowParent`
+    #[must_use]
+    pub fn with_sampler_choices(
+        mut self,
+        root: SamplerChoice,
+        remote_parent: SamplerChoice,
+    ) -> Self {
+        self.rate_for_roots = matches!(root, SamplerChoice::Ratio | SamplerChoice::FollowParent);
+        self.rate_for_remote_parents = remote_parent == SamplerChoice::Ratio;
+        self
+    }
+
+    /// Whether the spans of the trace a local root span with the parent in
+    /// `cx` starts are held back
+    fn holds_back(&self, trace_id: TraceId, cx: &Context) -> bool {
+        let rate_applies = if cx.has_active_span() {
+            self.rate_for_remote_parents
+        } else {
+            self.rate_for_roots
+        };
+        rate_applies && !self.in_sample(trace_id)
+    }
+
+    fn in_sample(&self, trace_id: TraceId) -> bool {
+        let sample_rate = self.sample_rate.rate();
+        if sample_rate >= 1.0 {
+            return true;
+        }
+        let upper_bound = (sample_rate.max(0.0) * (1u64 << 63) as f64) as u64;
+        let low = u128::from_be_bytes(trace_id.to_bytes()) as u64;
+        (low >> 1) < upper_bound
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for ErrorTraceProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        let trace_id = span.span_context().trace_id();
+        let is_local_root = !cx.has_active_span() || cx.span().span_context().is_remote();
+        if is_local_root
+            && self.holds_back(trace_id, cx)
+            && let Ok(mut pending) = self.pending.lock()
+        {
+            if !pending.contains_key(&trace_id) {
+                evict(&mut pending);
+            }
+            let trace = pending.entry(trace_id).or_insert_with(PendingTrace::new);
+            if trace.state == PendingState::Dropped {
+                *trace = PendingTrace::new();
+            }
+            trace.roots += 1;
+        }
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        let trace_id = span.span_context.trace_id();
+        let is_local_root = span.parent_span_id == SpanId::INVALID || span.parent_span_is_remote;
+        let is_error = matches!(span.status, Status::Error { .. });
+        let Ok(mut pending) = self.pending.lock() else {
+            return;
+        };
+        let Some(trace) = pending.get_mut(&trace_id) else {
+            // in the sample, or the caller decided
+            drop(pending);
+            self.inner.on_end(span);
+            return;
+        };
+        match trace.state {
+            PendingState::Kept => {
+                if is_local_root {
+                    trace.roots = trace.roots.saturating_sub(1);
+                }
+                drop(pending);
+                self.inner.on_end(span);
+                return;
+            }
+            // the roots have ended, so only a failing span is kept
+            PendingState::Dropped => {
+                drop(pending);
+                if is_error {
+                    self.inner.on_end(span);
+                }
+                return;
+            }
+            PendingState::Holding => {}
+        }
+        if !is_local_root {
+            if trace.spans.len() < MAX_BUFFERED_SPANS {
+                trace.spans.push(span);
+            }
+            return;
+        }
+        trace.roots = trace.roots.saturating_sub(1);
+        // the rate may have been raised since the trace started
+        if is_error || self.in_sample(trace_id) {
+            trace.state = PendingState::Kept;
+            let spans = std::mem::take(&mut trace.spans);
+            if trace.roots == 0 {
+                pending.remove(&trace_id);
+            }
+            drop(pending);
+            for span in spans {
+                self.inner.on_end(span);
+            }
+            self.inner.on_end(span);
+        } else if trace.roots == 0 {
+            trace.state = PendingState::Dropped;
+            trace.spans = Vec::new();
+        } else if trace.spans.len() < MAX_BUFFERED_SPANS {
+            // kept with the other roots if one of them fails
+            trace.spans.push(span);
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{SpanContext, TraceFlags, TraceState, Tracer, TracerProvider as _};
+    use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+    use std::sync::Arc;
+
+    /// Keeps the names of the spans passed on
+    #[derive(Debug, Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Recorder {
+        fn names(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl SpanProcessor for Recorder {
+        fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+        fn on_end(&self, span: SpanData) {
+            self.0.lock().unwrap().push(span.name.into_owned());
+        }
+
+        fn force_flush(&self) -> OTelSdkResult {
+            Ok(())
+        }
+
+        fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+            Ok(())
+        }
+    }
+
+    /// Run a trace of a root span with `children` child spans through an
+    /// `ErrorTraceProcessor` with `sample_rate`, and return the spans passed on
+    fn run_trace(sample_rate: f64, children: usize, root_status: Status) -> Vec<String> {
+        let parent = Context::new();
+        run_trace_with_parent(
+            &parent,
+            SamplerChoice::FollowParent,
+            sample_rate,
+            children,
+            root_status,
+        )
+    }
+
+    /// Like `run_trace()`, with the root span under the parent in `parent`,
+    /// sampled by `remote_parent`
+    fn run_trace_with_parent(
+        parent: &Context,
+        remote_parent: SamplerChoice,
+        sample_rate: f64,
+        children: usize,
+        root_status: Status,
+    ) -> Vec<String> {
+        let recorder = Recorder::default();
+        let provider = SdkTracerProvider::builder()
+            .with_sampler(Sampler::AlwaysOn)
+            .with_span_processor(
+                ErrorTraceProcessor::new(recorder.clone(), sample_rate)
+                    .with_sampler_choices(SamplerChoice::Ratio, remote_parent),
+            )
+            .build();
+        let tracer = provider.tracer("test");
+        let root = tracer.start_with_context("root", parent);
+        let cx = parent.with_span(root);
+        for _ in 0..children {
+            tracer.start_with_context("child", &cx).end();
+        }
+        cx.span().set_status(root_status);
+        cx.span().end();
+        recorder.names()
+    }
+
+    #[test]
+    fn traces_in_the_sample_are_passed_on() {
+        let names = run_trace(1.0, 2, Status::Unset);
+        assert_eq!(names, ["child", "child", "root"]);
+    }
+
+    #[test]
+    fn successful_traces_outside_the_sample_are_dropped() {
+        assert!(run_trace(0.0, 2, Status::Unset).is_empty());
+        assert!(run_trace(0.0, 2, Status::Ok).is_empty());
+    }
+
+    #[test]
+    fn failed_traces_outside_the_sample_are_flushed() {
+        let names = run_trace(0.0, 2, Status::error("failed"));
+        assert_eq!(names, ["child", "child", "root"]);
+    }
+
+    #[test]
+    fn held_back_spans_are_capped() {
+        let names = run_trace(0.0, MAX_BUFFERED_SPANS + 10, Status::error("failed"));
+        assert_eq!(names.len(), MAX_BUFFERED_SPANS + 1);
+        assert_eq!(names.last().map(String::as_str), Some("root"));
+    }
+
+    fn remote_parent(flags: TraceFlags) -> Context {
+        Context::new().with_remote_span_context(SpanContext::new(
+            TraceId::from(0x4bf9_2f35_77b3_4da6_a3ce_929d_0e0e_4736),
+            SpanId::from(0x00f0_67aa_0ba9_02b7),
+            flags,
+            true,
+            TraceState::default(),
+        ))
+    }
+
+    #[test]
+    fn traces_of_a_sampled_caller_are_passed_on() {
+        let parent = remote_parent(TraceFlags::SAMPLED);
+        let names =
+            run_trace_with_parent(&parent, SamplerChoice::FollowParent, 0.0, 2, Status::Unset);
+        assert_eq!(names, ["child", "child", "root"]);
+    }
+
+    /// The sampler has already dropped them when it follows the caller
+    #[test]
+    fn traces_of_an_unsampled_caller_are_left_to_the_sampler() {
+        let parent = remote_parent(TraceFlags::default());
+        let names =
+            run_trace_with_parent(&parent, SamplerChoice::FollowParent, 0.0, 1, Status::Unset);
+        assert_eq!(names, ["child", "root"]);
+    }
+
+    #[test]
+    fn rate_applies_to_callers_with_the_ratio_sampler() {
+        let parent = remote_parent(TraceFlags::SAMPLED);
+        let names = run_trace_with_parent(&parent, SamplerChoice::Ratio, 0.0, 2, Status::Unset);
+        assert!(names.is_empty());
+    }
+
+    #[test]
+    fn local_roots_of_one_trace_are_held_back_together() {
+        let recorder = Recorder::default();
+        let provider = SdkTracerProvider::builder()
+            .with_sampler(Sampler::AlwaysOn)
+            .with_span_processor(
+                ErrorTraceProcessor::new(recorder.clone(), 0.0)
+                    .with_sampler_choices(SamplerChoice::Ratio, SamplerChoice::Ratio),
+            )
+            .build();
+        let tracer = provider.tracer("test");
+        let parent = remote_parent(TraceFlags::SAMPLED);
+        let first = parent.with_span(tracer.start_with_context("first", &parent));
+        let second = parent.with_span(tracer.start_with_context("second", &parent));
+        tracer.start_with_context("first child", &first).end();
+        tracer.start_with_context("second child", &second).end();
+        first.span().end();
+        assert!(recorder.names().is_empty());
+
+        second.span().set_status(Status::error("failed"));
+        second.span().end();
+        assert_eq!(
+            recorder.names(),
+            ["first child", "second child", "first", "second"]
+        );
+    }
+
+    fn pending_trace(state: PendingState) -> PendingTrace {
+        PendingTrace {
+            state,
+            ..PendingTrace::new()
+        }
+    }
+
+    #[test]
+    fn ended_traces_are_evicted_first() {
+        let mut pending = HashMap::new();
+        for i in 0..MAX_PENDING_TRACES {
+            let state = if i % 2 == 0 {
+                PendingState::Holding
+            } else {
+                PendingState::Dropped
+            };
+            pending.insert(TraceId::from(i as u128 + 1), pending_trace(state));
+        }
+        evict(&mut pending);
+        assert_eq!(pending.len(), MAX_PENDING_TRACES / 2);
+        assert!(
+            pending
+                .values()
+                .all(|trace| trace.state == PendingState::Holding)
+        );
+    }
+
+    #[test]
+    fn oldest_trace_is_evicted_when_all_are_held_back() {
+        let mut pending = HashMap::new();
+        for i in 0..MAX_PENDING_TRACES {
+            pending.insert(
+                TraceId::from(i as u128 + 1),
+                pending_trace(PendingState::Holding),
+            );
+        }
+        if let Some(first) = pending.get_mut(&TraceId::from(1)) {
+            first.started -= Duration::from_secs(1);
+        }
+        evict(&mut pending);
+        assert_eq!(pending.len(), MAX_PENDING_TRACES - 1);
+        assert!(!pending.contains_key(&TraceId::from(1)));
+    }
+}
@@ -0,0 +1,176 @@
+//! Local rate limiting of request spans
+
+use opentelemetry::metrics::Counter;
+use std::{
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+/// Token bucket allowing a number of spans per second.
+///
+/// The bucket holds up to one second worth of spans, so bursts up to
+/// the limit are allowed.
+#[derive(Debug)]
+pub(crate) struct SpanRateLimiter {
+    spans_per_second: f64,
+    bucket: Mutex<Bucket>,
+    dropped: OnceLock<Counter<u64>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl SpanRateLimiter {
+    pub(crate) fn new(spans_per_second: u32) -> Self {
+        let spans_per_second = f64::from(spans_per_second);
+        SpanRateLimiter {
+            spans_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: spans_per_second,
+                last_refill: Instant::now(),
+            }),
+            dropped: OnceLock::new(),
+        }
+    }
+
+    /// Take a token from the bucket, returns false (and counts the
+    /// dropped span) if the bucket is empty.
+    pub(crate) fn try_acquire(&self) -> bool {
+        let acquired = self.take_token(Instant::now());
+        if !acquired {
+            // created on first use, so a meter provider installed after
+            // the layer is built is still used
+            self.dropped
+                .get_or_init(|| {
+                    opentelemetry::global::meter("axum-otlp-honeycomb")
+                        .u64_counter("http.server.spans.dropped")
+                        .with_description("Request spans dropped by the span rate limit")
+                        .build()
+                })
+                .add(1, &[]);
+        }
+        acquired
+    }
+
+    /// Refill the bucket for the time until `now`, and take a token if there is one
+    fn take_token(&self, now: Instant) -> bool {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let refill = now
+            .saturating_duration_since(bucket.last_refill)
+            .as_secs_f64()
+            * self.spans_per_second;
+        bucket.tokens = (bucket.tokens + refill).min(self.spans_per_second);
+        bucket.last_refill = bucket.last_refill.max(now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// A limiter of `spans_per_second` and the time it was created
+    fn limiter(spans_per_second: u32) -> (SpanRateLimiter, Instant) {
+        let limiter = SpanRateLimiter::new(spans_per_second);
+        let start = limiter.bucket.lock().unwrap().last_refill;
+        (limiter, start)
+    }
+
+    /// The number of tokens taken of `attempts` at `now`
+    fn take_tokens(limiter: &SpanRateLimiter, now: Instant, attempts: usize) -> usize {
+        (0..attempts).filter(|_| limiter.take_token(now)).count()
+    }
+
+    #[test]
+    fn burst_up_to_the_limit_is_allowed() {
+        let (limiter, start) = limiter(10);
+        assert_eq!(take_tokens(&limiter, start, 15), 10);
+    }
+
+    #[test]
+    fn exhausted_bucket_refuses_spans() {
+        let (limiter, start) = limiter(3);
+        assert_eq!(take_tokens(&limiter, start, 3), 3);
+        assert!(!limiter.take_token(start));
+        assert!(!limiter.take_token(start + Duration::from_millis(300)));
+    }
+
+    #[test]
+    fn bucket_is_refilled_with_the_time_passed() {
+        let (limiter, start) = limiter(10);
+        assert_eq!(take_tokens(&limiter, start, 10), 10);
+        assert_eq!(
+            take_tokens(&limiter, start + Duration::from_millis(250), 10),
+            2
+        );
+        // the half token left over is kept
+        assert_eq!(
+            take_tokens(&limiter, start + Duration::from_millis(300), 10),
+            1
+        );
+    }
+
+    #[test]
+    fn refill_is_capped_at_one_second_of_spans() {
+        let (limiter, start) = limiter(5);
+        assert_eq!(take_tokens(&limiter, start, 5), 5);
+        assert_eq!(
+            take_tokens(&limiter, start + Duration::from_secs(60), 20),
+            5
+        );
+    }
+
+    #[test]
+    fn earlier_instant_does_not_refill() {
+        let (limiter, start) = limiter(2);
+        let later = start + Duration::from_secs(1);
+        assert_eq!(take_tokens(&limiter, later, 2), 2);
+        assert_eq!(take_tokens(&limiter, start, 2), 0);
+        assert_eq!(take_tokens(&limiter, later, 2), 0);
+    }
+
+    #[cfg(all(feature = "metrics", feature = "testing"))]
+    #[test]
+    fn dropped_spans_are_counted() {
+        use opentelemetry_sdk::metrics::{
+            InMemoryMetricExporter, PeriodicReader, SdkMeterProvider,
+            data::{AggregatedMetrics, MetricData},
+        };
+
+        let exporter = InMemoryMetricExporter::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter.clone()).build())
+            .build();
+        opentelemetry::global::set_meter_provider(provider.clone());
+
+        let limiter = SpanRateLimiter::new(2);
+        let acquired = (0..5).filter(|_| limiter.try_acquire()).count();
+        provider.force_flush().unwrap();
+
+        let dropped: u64 = exporter
+            .get_finished_metrics()
+            .unwrap()
+            .iter()
+            .flat_map(|metrics| metrics.scope_metrics())
+            .flat_map(|scope| scope.metrics())
+            .filter(|metric| metric.name() == "http.server.spans.dropped")
+            .map(|metric| match metric.data() {
+                AggregatedMetrics::U64(MetricData::Sum(sum)) => {
+                    sum.data_points().map(|point| point.value()).sum()
+                }
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(dropped, 5 - acquired as u64);
+        assert!(dropped >= 3);
+    }
+}
@@ -0,0 +1,126 @@
+//! Body wrapper for the response
+//!
+//! Keeps the request span alive until the response body has been sent,
+//! and records data that is only available at the end of the body.
+//!
+//! The frames are handed on as they are polled, nothing is buffered or copied,
+//! so the memory use does not grow with the size of the body. Only the
+//! trailers are looked at, and only the ones in `with_recorded_trailers`.
+
+use crate::axum_layer::{AxumOtelLayer, ERROR_LOG_TARGET, record_trailers};
+use http_body::{Body, Frame, SizeHint};
+use pin_project_lite::pin_project;
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tracing::Span;
+
+pin_project! {
+    /// Response body returned by [`AxumOtelService`].
+    ///
+    /// Forwards the frames of the inner body without buffering them.
+    ///
+    /// [`AxumOtelService`]: crate::axum_layer::AxumOtelService
+    pub struct ResponseBody<B> {
+        #[pin]
+        inner: B,
+        span: Span,
+        layer: Arc<AxumOtelLayer>,
+        // record `otel.status_code = OK` when the body is done, unless it failed
+        ok_status: bool,
+        // the end of the body has been sent or it failed, or no body is sent
+        ended: bool,
+    }
+
+    impl<B> PinnedDrop for ResponseBody<B> {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            // The client went away, or the server stopped sending the body
+            if !*this.ended {
+                record_body_error(
+                    this.span,
+                    this.layer,
+                    &"response body dropped before completion",
+                );
+            } else if *this.ok_status {
+                this.span.record("otel.status_code", "OK");
+            }
+        }
+    }
+}
+
+impl<B: Body> ResponseBody<B> {
+    /// `sends_body` is false for responses whose body is never polled, eg.
+    /// to a `HEAD` request, so they are not taken for cut off responses
+    pub(crate) fn new(
+        inner: B,
+        span: Span,
+        layer: Arc<AxumOtelLayer>,
+        ok_status: bool,
+        sends_body: bool,
+    ) -> Self {
+        ResponseBody {
+            span,
+            layer,
+            ok_status,
+            ended: !sends_body || inner.is_end_stream(),
+            inner,
+        }
+    }
+}
+
+impl<B> Body for ResponseBody<B>
+where
+    B: Body,
+    B::Error: std::fmt::Display,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+        let frame = futures_util::ready!(this.inner.as_mut().poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                // hyper stops polling at the end of the stream
+                *this.ended = this.inner.is_end_stream();
+                if let Some(trailers) = frame.trailers_ref()
+                    && record_trailers(this.span, this.layer, trailers)
+                {
+                    *this.ok_status = false;
+                }
+            }
+            // The head has been sent with its status, but the response is cut off
+            Some(Err(err)) => {
+                record_body_error(this.span, this.layer, err);
+                *this.ok_status = false;
+                *this.ended = true;
+            }
+            None => *this.ended = true,
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn record_body_error(span: &Span, layer: &AxumOtelLayer, err: &dyn std::fmt::Display) {
+    if layer.error_logs && !span.is_none() {
+        let _guard = span.enter();
+        tracing::error!(target: ERROR_LOG_TARGET, "HTTP response body failed: {err}");
+    }
+    span.record("otel.status_code", "ERROR");
+    span.record("error.source", "body");
+    span.record("exception.message", err.to_string());
+}
@@ -0,0 +1,193 @@
+//! Sampler with a choice for each kind of parent
+
+use crate::config::SamplerChoice;
+use opentelemetry::{
+    Context, KeyValue,
+    trace::{Link, SamplingDecision, SamplingResult, SpanKind, TraceContextExt, TraceId},
+};
+use opentelemetry_sdk::trace::{Sampler, ShouldSample};
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+
+/// Handle to change the sample rate while the program runs, eg. to keep all
+/// traces during an incident
+///
+/// Returned by `init_otlp_layer_with_handle()` and `OtelGuard::sample_rate()`.
+/// The new rate is used for the sampling decisions from then on, for the
+/// [`SamplerChoice::Ratio`] samplers and by the `ErrorTraceProcessor`.
+#[derive(Debug, Clone)]
+pub struct SampleRateHandle {
+    /// the bits of the `f64` rate
+    rate: Arc<AtomicU64>,
+}
+
+impl SampleRateHandle {
+    pub(crate) fn new(rate: f64) -> Self {
+        let handle = SampleRateHandle {
+            rate: Arc::new(AtomicU64::new(1.0_f64.to_bits())),
+        };
+        handle.set_rate(rate);
+        handle
+    }
+
+    /// Set the fraction of traces to keep, clamped to 0.0..=1.0. NaN is ignored.
+    pub fn set_rate(&self, rate: f64) {
+        if !rate.is_nan() {
+            self.rate
+                .store(rate.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// The current sample rate
+    #[must_use]
+    pub fn rate(&self) -> f64 {
+        f64::from_bits(self.rate.load(Ordering::Relaxed))
+    }
+}
+
+/// Like `Sampler::ParentBased`, but with separate samplers for spans with a
+/// remote parent (from `traceparent`) and for spans with a local parent.
+#[derive(Debug, Clone)]
+pub(crate) struct ParentBasedSampler {
+    root: SamplerChoice,
+    remote_parent: SamplerChoice,
+    local_parent: SamplerChoice,
+    sample_rate: SampleRateHandle,
+}
+
+impl ParentBasedSampler {
+    /// The current rate of `sample_rate` is used for [`SamplerChoice::Ratio`],
+    /// and for [`SamplerChoice::FollowParent`] without a parent
+    pub(crate) fn new(
+        root: SamplerChoice,
+        remote_parent: SamplerChoice,
+        local_parent: SamplerChoice,
+        sample_rate: SampleRateHandle,
+    ) -> Self {
+        ParentBasedSampler {
+            root,
+            remote_parent,
+            local_parent,
+            sample_rate,
+        }
+    }
+
+    /// The sampler of `choice` for a span without a parent that decides
+    fn sampler(&self, choice: SamplerChoice) -> Sampler {
+        match choice {
+            SamplerChoice::AlwaysOn => Sampler::AlwaysOn,
+            SamplerChoice::AlwaysOff => Sampler::AlwaysOff,
+            SamplerChoice::Ratio | SamplerChoice::FollowParent => {
+                Sampler::TraceIdRatioBased(self.sample_rate.rate())
+            }
+        }
+    }
+}
+
+/// The decision of the parent span in `cx`, keeping its `tracestate`
+fn parent_decision(cx: &Context) -> SamplingResult {
+    let span = cx.span();
+    let span_context = span.span_context();
+    SamplingResult {
+        decision: if span_context.is_sampled() {
+            SamplingDecision::RecordAndSample
+        } else {
+            SamplingDecision::Drop
+        },
+        attributes: Vec::new(),
+        trace_state: span_context.trace_state().clone(),
+    }
+}
+
+impl ShouldSample for ParentBasedSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        let parent = parent_context.filter(|cx| cx.has_active_span());
+        let choice = match parent {
+            None => self.root,
+            Some(cx) if cx.span().span_context().is_remote() => self.remote_parent,
+            Some(_) => self.local_parent,
+        };
+        if let (SamplerChoice::FollowParent, Some(cx)) = (choice, parent) {
+            return parent_decision(cx);
+        }
+        self.sampler(choice).should_sample(
+            parent_context,
+            trace_id,
+            name,
+            span_kind,
+            attributes,
+            links,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{SpanContext, SpanId, TraceFlags, TraceState};
+
+    fn follow_parent(rate: f64) -> ParentBasedSampler {
+        ParentBasedSampler::new(
+            SamplerChoice::FollowParent,
+            SamplerChoice::FollowParent,
+            SamplerChoice::FollowParent,
+            SampleRateHandle::new(rate),
+        )
+    }
+
+    fn decision(sampler: &ParentBasedSampler, parent: Option<&Context>) -> SamplingDecision {
+        sampler
+            .should_sample(
+                parent,
+                TraceId::from(0x4bf9_2f35_77b3_4da6_a3ce_929d_0e0e_4736),
+                "span",
+                &SpanKind::Server,
+                &[],
+                &[],
+            )
+            .decision
+    }
+
+    fn remote_parent(flags: TraceFlags) -> Context {
+        Context::new().with_remote_span_context(SpanContext::new(
+            TraceId::from(0x4bf9_2f35_77b3_4da6_a3ce_929d_0e0e_4736),
+            SpanId::from(0x00f0_67aa_0ba9_02b7),
+            flags,
+            true,
+            TraceState::default(),
+        ))
+    }
+
+    #[test]
+    fn follow_parent_samples_root_spans_at_the_rate() {
+        assert_eq!(
+            decision(&follow_parent(1.0), None),
+            SamplingDecision::RecordAndSample
+        );
+        assert_eq!(decision(&follow_parent(0.0), None), SamplingDecision::Drop);
+    }
+
+    #[test]
+    fn follow_parent_keeps_the_decision_of_the_parent() {
+        let unsampled = remote_parent(TraceFlags::default());
+        assert_eq!(
+            decision(&follow_parent(1.0), Some(&unsampled)),
+            SamplingDecision::Drop
+        );
+        let sampled = remote_parent(TraceFlags::SAMPLED);
+        assert_eq!(
+            decision(&follow_parent(0.0), Some(&sampled)),
+            SamplingDecision::RecordAndSample
+        );
+    }
+}
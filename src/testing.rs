@@ -0,0 +1,345 @@
+//! Helpers for testing code that uses this crate
+//!
+//! Only available with the `testing` feature.
+//!
+//! The layers created with an in-memory exporter export every span and log
+//! record when it ends, so they can be inspected right after the request:
+//!
+//! ```
+//! use axum::{Router, body::Body, routing::get};
+//! use axum_otlp_honeycomb::{opentelemetry_tracing_layer, testing::*};
+//! use tower::ServiceExt;
+//! use tracing_subscriber::prelude::*;
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let spans = InMemorySpanExporter::default();
+//! let _subscriber = tracing_subscriber::registry()
+//!     .with(init_otlp_layer_with_exporter(spans.clone()))
+//!     .set_default();
+//!
+//! let app = Router::new()
+//!     .route("/users/{id}", get(|| async { "" }))
+//!     .layer(opentelemetry_tracing_layer());
+//! let request = http::Request::get("/users/42").body(Body::empty()).unwrap();
+//! app.oneshot(request).await.unwrap();
+//!
+//! let span = &spans.get_finished_spans().unwrap()[0];
+//! assert_eq!(span.name, "GET /users/{id}");
+//! assert_eq!(get_span_str(span, "http.route"), Some("/users/{id}"));
+//! // recorded from a `u16`, which ends up as a string attribute
+//! assert_eq!(get_span_str(span, "http.response.status_code"), Some("200"));
+//! # }
+//! ```
+
+#[cfg(any(feature = "traces", feature = "logs"))]
+use crate::OtlpConfig;
+#[cfg(feature = "logs")]
+use crate::{AxumOtelEventLogger, log_layer};
+#[cfg(feature = "traces")]
+use crate::{install_propagators, tracing_layer};
+use opentelemetry::Value;
+#[cfg(feature = "logs")]
+use opentelemetry::logs::AnyValue;
+#[cfg(feature = "logs")]
+pub use opentelemetry_sdk::logs::InMemoryLogExporter;
+#[cfg(feature = "logs")]
+use opentelemetry_sdk::logs::{LogExporter, SdkLogRecord, SdkLogger, SdkLoggerProvider};
+pub use opentelemetry_sdk::trace::InMemorySpanExporter;
+use opentelemetry_sdk::trace::SpanData;
+#[cfg(feature = "traces")]
+use opentelemetry_sdk::trace::{SdkTracerProvider, ShouldSample, SpanExporter, Tracer};
+#[cfg(feature = "traces")]
+use tracing_core::Subscriber;
+#[cfg(feature = "traces")]
+use tracing_opentelemetry::OpenTelemetryLayer;
+#[cfg(feature = "traces")]
+use tracing_subscriber::registry::LookupSpan;
+
+#[cfg(feature = "traces")]
+/// Create the tracing layer exporting to `exporter`, usually an
+/// `InMemorySpanExporter`, instead of Honeycomb
+///
+/// All traces are sampled, and the propagators of `OtlpConfig::default()`
+/// are installed so incoming `traceparent` headers are picked up. The
+/// `tracestate` of the caller is kept in the span context, and sent on with
+/// the `traceparent` of the response and of the calls made by the handler:
+///
+/// ```
+/// use axum::{Router, body::Body, routing::get};
+/// use axum_otlp_honeycomb::{opentelemetry_tracing_layer, testing::*};
+/// use std::collections::HashMap;
+/// use tower::ServiceExt;
+/// use tracing_opentelemetry::OpenTelemetrySpanExt;
+/// use tracing_subscriber::prelude::*;
+///
+/// /// The `tracestate` the handler would send downstream
+/// async fn outgoing() -> String {
+///     let mut headers = HashMap::new();
+///     let cx = tracing::Span::current().context();
+///     opentelemetry::global::get_text_map_propagator(|propagator| {
+///         propagator.inject_context(&cx, &mut headers);
+///     });
+///     headers.remove("tracestate").unwrap_or_default()
+/// }
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let exporter = InMemorySpanExporter::default();
+/// let _subscriber = tracing_subscriber::registry()
+///     .with(init_otlp_layer_with_exporter(exporter.clone()))
+///     .set_default();
+/// let app = Router::new()
+///     .route("/", get(outgoing))
+///     .layer(opentelemetry_tracing_layer());
+/// let request = http::Request::get("/")
+///     .header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+///     .header("tracestate", "vendor=value")
+///     .body(Body::empty())
+///     .unwrap();
+/// let response = app.oneshot(request).await.unwrap();
+/// assert_eq!(response.headers()["tracestate"], "vendor=value");
+/// let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+/// assert_eq!(body, "vendor=value");
+///
+/// let spans = exporter.get_finished_spans().unwrap();
+/// assert_eq!(spans[0].span_context.trace_state().get("vendor"), Some("value"));
+/// # }
+/// ```
+pub fn init_otlp_layer_with_exporter<S, E>(exporter: E) -> OpenTelemetryLayer<S, Tracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    E: SpanExporter + 'static,
+{
+    let config = OtlpConfig::default();
+    install_propagators(&config);
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter)
+        .build();
+    tracing_layer(&provider, &config)
+}
+
+#[cfg(feature = "traces")]
+/// Create the tracing layer like `init_otlp_layer_with_exporter()`, with
+/// `sampler` deciding which traces are sampled
+///
+/// The sampling decision is propagated in the sampled flag of the `traceparent`
+/// headers, both in the response and in the calls made by the handler:
+///
+/// ```
+/// use axum::{Router, body::Body, routing::get};
+/// use axum_otlp_honeycomb::{opentelemetry_tracing_layer, testing::*};
+/// use opentelemetry_sdk::trace::Sampler;
+/// use std::collections::HashMap;
+/// use tower::ServiceExt;
+/// use tracing_opentelemetry::OpenTelemetrySpanExt;
+/// use tracing_subscriber::prelude::*;
+///
+/// /// The `traceparent` the handler would send downstream
+/// async fn outgoing() -> String {
+///     let mut headers = HashMap::new();
+///     let cx = tracing::Span::current().context();
+///     opentelemetry::global::get_text_map_propagator(|propagator| {
+///         propagator.inject_context(&cx, &mut headers);
+///     });
+///     headers.remove("traceparent").unwrap_or_default()
+/// }
+///
+/// async fn traceparents(sampler: Sampler, incoming: Option<&str>) -> (String, String) {
+///     let _subscriber = tracing_subscriber::registry()
+///         .with(init_otlp_layer_with_sampler(InMemorySpanExporter::default(), sampler))
+///         .set_default();
+///     let app = Router::new()
+///         .route("/", get(outgoing))
+///         .layer(opentelemetry_tracing_layer());
+///     let mut request = http::Request::get("/");
+///     if let Some(traceparent) = incoming {
+///         request = request.header("traceparent", traceparent);
+///     }
+///     let response = app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+///     let returned = response.headers()["traceparent"].to_str().unwrap().to_string();
+///     let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+///     (returned, String::from_utf8(body.to_vec()).unwrap())
+/// }
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// // the decision made at the edge
+/// let (returned, sent) = traceparents(Sampler::AlwaysOff, None).await;
+/// assert!(returned.ends_with("-00") && sent.ends_with("-00"));
+/// let (returned, sent) = traceparents(Sampler::AlwaysOn, None).await;
+/// assert!(returned.ends_with("-01") && sent.ends_with("-01"));
+///
+/// // the decision of the caller
+/// let follow_parent = || Sampler::ParentBased(Box::new(Sampler::AlwaysOn));
+/// let unsampled = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00";
+/// let (returned, sent) = traceparents(follow_parent(), Some(unsampled)).await;
+/// assert!(returned.ends_with("-00") && sent.ends_with("-00"));
+/// let sampled = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+/// let (returned, sent) = traceparents(follow_parent(), Some(sampled)).await;
+/// assert!(returned.ends_with("-01") && sent.ends_with("-01"));
+/// assert!(sent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+/// # }
+/// ```
+pub fn init_otlp_layer_with_sampler<S, E, F>(
+    exporter: E,
+    sampler: F,
+) -> OpenTelemetryLayer<S, Tracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    E: SpanExporter + 'static,
+    F: ShouldSample + 'static,
+{
+    let config = OtlpConfig::default();
+    install_propagators(&config);
+    let provider = SdkTracerProvider::builder()
+        .with_sampler(sampler)
+        .with_simple_exporter(exporter)
+        .build();
+    tracing_layer(&provider, &config)
+}
+
+#[cfg(feature = "logs")]
+/// Create the log layer exporting to `exporter`, usually an
+/// `InMemoryLogExporter`, instead of Honeycomb
+///
+/// ```
+/// use axum_otlp_honeycomb::testing::*;
+/// use opentelemetry::logs::AnyValue;
+/// use tracing_subscriber::prelude::*;
+///
+/// let logs = InMemoryLogExporter::default();
+/// let spans = InMemorySpanExporter::default();
+/// let _subscriber = tracing_subscriber::registry()
+///     .with(init_otlp_layer_with_exporter(spans.clone()))
+///     .with(init_otlp_log_layer_with_exporter(logs.clone()))
+///     .set_default();
+///
+/// tracing::info_span!("work").in_scope(|| tracing::info!(user = 42, "hello"));
+///
+/// let record = &logs.get_emitted_logs().unwrap()[0].record;
+/// assert_eq!(record.body(), Some(&AnyValue::from("hello")));
+/// assert_eq!(get_int(record, "user"), Some(42));
+/// let span = &spans.get_finished_spans().unwrap()[0];
+/// assert_eq!(
+///     record.trace_context().map(|context| context.trace_id),
+///     Some(span.span_context.trace_id())
+/// );
+/// ```
+pub fn init_otlp_log_layer_with_exporter<E>(
+    exporter: E,
+) -> AxumOtelEventLogger<SdkLoggerProvider, SdkLogger>
+where
+    E: LogExporter + 'static,
+{
+    let provider = SdkLoggerProvider::builder()
+        .with_simple_exporter(exporter)
+        .build();
+    log_layer(&provider, &OtlpConfig::default())
+}
+
+/// Get the attribute `key` from a recorded span
+#[must_use]
+pub fn get_span_attribute<'a>(span: &'a SpanData, key: &str) -> Option<&'a Value> {
+    span.attributes
+        .iter()
+        .find(|kv| kv.key.as_str() == key)
+        .map(|kv| &kv.value)
+}
+
+/// Get a string attribute of a span, `None` if it is missing or not a string
+#[must_use]
+pub fn get_span_str<'a>(span: &'a SpanData, key: &str) -> Option<&'a str> {
+    match get_span_attribute(span, key)? {
+        Value::String(s) => Some(s.as_str()),
+        _ => None,
+    }
+}
+
+/// Get an integer attribute of a span, `None` if it is missing or not an integer
+#[must_use]
+pub fn get_span_int(span: &SpanData, key: &str) -> Option<i64> {
+    match get_span_attribute(span, key)? {
+        Value::I64(i) => Some(*i),
+        _ => None,
+    }
+}
+
+/// Get a boolean attribute of a span, `None` if it is missing or not a boolean
+#[must_use]
+pub fn get_span_bool(span: &SpanData, key: &str) -> Option<bool> {
+    match get_span_attribute(span, key)? {
+        Value::Bool(b) => Some(*b),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "logs")]
+/// Get the attribute `key` from a recorded log record
+#[must_use]
+pub fn get_attribute<'a>(record: &'a SdkLogRecord, key: &str) -> Option<&'a AnyValue> {
+    record
+        .attributes_iter()
+        .find(|(k, _)| k.as_str() == key)
+        .map(|(_, v)| v)
+}
+
+#[cfg(feature = "logs")]
+/// Get an integer attribute, `None` if it is missing or not an integer
+#[must_use]
+pub fn get_int(record: &SdkLogRecord, key: &str) -> Option<i64> {
+    match get_attribute(record, key)? {
+        AnyValue::Int(i) => Some(*i),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "logs")]
+/// Get a floating point attribute, `None` if it is missing or not a double
+#[must_use]
+pub fn get_double(record: &SdkLogRecord, key: &str) -> Option<f64> {
+    match get_attribute(record, key)? {
+        AnyValue::Double(d) => Some(*d),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "logs")]
+/// Get a string attribute, `None` if it is missing or not a string
+#[must_use]
+pub fn get_str<'a>(record: &'a SdkLogRecord, key: &str) -> Option<&'a str> {
+    match get_attribute(record, key)? {
+        AnyValue::String(s) => Some(s.as_str()),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "logs")]
+/// Get a boolean attribute, `None` if it is missing or not a boolean
+#[must_use]
+pub fn get_bool(record: &SdkLogRecord, key: &str) -> Option<bool> {
+    match get_attribute(record, key)? {
+        AnyValue::Boolean(b) => Some(*b),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "logs")]
+/// Get a bytes attribute, `None` if it is missing or not bytes
+#[must_use]
+pub fn get_bytes<'a>(record: &'a SdkLogRecord, key: &str) -> Option<&'a [u8]> {
+    match get_attribute(record, key)? {
+        AnyValue::Bytes(b) => Some(b.as_slice()),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "logs")]
+/// Get a list attribute, `None` if it is missing or not a list
+#[must_use]
+pub fn get_list<'a>(record: &'a SdkLogRecord, key: &str) -> Option<&'a [AnyValue]> {
+    match get_attribute(record, key)? {
+        AnyValue::ListAny(l) => Some(l.as_slice()),
+        _ => None,
+    }
+}
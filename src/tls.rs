@@ -0,0 +1,113 @@
+//! Custom TLS for the exporters, see `OtlpConfigBuilder::root_certificate()`
+
+use crate::{InitError, OtlpConfig, config::ClientIdentity};
+use opentelemetry_otlp::tonic_types::transport::{Certificate, ClientTlsConfig, Identity};
+use std::time::Duration;
+
+/// The default of `OTEL_EXPORTER_OTLP_TIMEOUT`
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn is_configured(config: &OtlpConfig) -> bool {
+    !config.root_certificates.is_empty() || config.client_identity.is_some()
+}
+
+/// The HTTP client trusting the configured roots, `None` to let the exporter
+/// build its default client
+pub(crate) fn http_client(
+    config: &OtlpConfig,
+) -> Result<Option<reqwest::blocking::Client>, InitError> {
+    if !is_configured(config) {
+        return Ok(None);
+    }
+    // the exporter only applies its timeout to the clients it builds itself
+    let timeout = config.timeout.unwrap_or_else(|| {
+        std::env::var("OTEL_EXPORTER_OTLP_TIMEOUT")
+            .ok()
+            .and_then(|millis| millis.parse().ok())
+            .map_or(DEFAULT_TIMEOUT, Duration::from_millis)
+    });
+    let mut builder = reqwest::blocking::Client::builder().timeout(timeout);
+    for pem in &config.root_certificates {
+        for certificate in root_certificates(pem)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if let Some(identity) = &config.client_identity {
+        builder = builder.identity(client_identity(identity)?);
+    }
+    // the blocking client can't be built on an async runtime thread
+    std::thread::spawn(move || builder.build())
+        .join()
+        .map_err(|_| InitError::Tls("building the HTTP client panicked".to_string()))?
+        .map(Some)
+        .map_err(tls_error)
+}
+
+/// The gRPC TLS config trusting the configured roots, `None` for the default
+///
+/// tonic only parses the PEM when it connects, so it is checked here by
+/// building an HTTP client with it, to fail like `http_client()` does.
+pub(crate) fn tonic_tls_config(config: &OtlpConfig) -> Result<Option<ClientTlsConfig>, InitError> {
+    if !is_configured(config) {
+        return Ok(None);
+    }
+    let mut check = reqwest::Client::builder();
+    let mut tls = ClientTlsConfig::new().with_native_roots();
+    for pem in &config.root_certificates {
+        for certificate in root_certificates(pem)? {
+            check = check.add_root_certificate(certificate);
+        }
+        tls = tls.ca_certificate(Certificate::from_pem(pem));
+    }
+    if let Some(identity) = &config.client_identity {
+        check = check.identity(client_identity(identity)?);
+        tls = tls.identity(Identity::from_pem(&identity.cert, &identity.key));
+    }
+    check.build().map_err(tls_error)?;
+    Ok(Some(tls))
+}
+
+/// The certificates of the PEM bundle `pem`, it is an error if there are none
+fn root_certificates(pem: &[u8]) -> Result<Vec<reqwest::Certificate>, InitError> {
+    let certificates = reqwest::Certificate::from_pem_bundle(pem).map_err(tls_error)?;
+    if certificates.is_empty() {
+        return Err(InitError::Tls(
+            "no certificate in the root certificate PEM".to_string(),
+        ));
+    }
+    Ok(certificates)
+}
+
+/// The client certificate and key of `identity`
+fn client_identity(identity: &ClientIdentity) -> Result<reqwest::Identity, InitError> {
+    let mut pem = identity.cert.clone();
+    pem.push(b'\n');
+    pem.extend_from_slice(&identity.key);
+    reqwest::Identity::from_pem(&pem).map_err(tls_error)
+}
+
+/// The error with its source, `reqwest::Error` alone only says "builder error"
+fn tls_error(err: reqwest::Error) -> InitError {
+    match std::error::Error::source(&err) {
+        Some(source) => InitError::Tls(format!("{err}: {source}")),
+        None => InitError::Tls(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grpc_error(pem: &str) -> bool {
+        let config = OtlpConfig::builder().root_certificate(pem).build();
+        matches!(tonic_tls_config(&config), Err(InitError::Tls(_)))
+    }
+
+    #[test]
+    fn invalid_root_certificate_fails_for_grpc() {
+        assert!(grpc_error("not a certificate"));
+        assert!(grpc_error(
+            "-----BEGIN CERTIFICATE-----\nbm90IGEgY2VydGlmaWNhdGU=\n-----END CERTIFICATE-----\n"
+        ));
+    }
+}
@@ -0,0 +1,172 @@
+//! The log records emitted for events
+
+use axum::{Router, body::Body, routing::get};
+use axum_otlp_honeycomb::{DuplicateKeys, opentelemetry_tracing_layer, testing::*};
+use http::StatusCode;
+use opentelemetry_sdk::logs::SdkLogRecord;
+use tower::ServiceExt;
+use tracing::Instrument;
+use tracing_subscriber::prelude::*;
+
+#[test]
+fn log_record_has_the_trace_context_of_its_span() {
+    let spans = InMemorySpanExporter::default();
+    let logs = InMemoryLogExporter::default();
+    let _subscriber = tracing_subscriber::registry()
+        .with(init_otlp_layer_with_exporter(spans.clone()))
+        .with(init_otlp_log_layer_with_exporter(logs.clone()))
+        .set_default();
+
+    tracing::info_span!("request").in_scope(|| tracing::info!("in the span"));
+
+    let spans = spans.get_finished_spans().unwrap();
+    let logs = logs.get_emitted_logs().unwrap();
+    let trace_context = logs[0].record.trace_context().unwrap();
+    assert_eq!(trace_context.trace_id, spans[0].span_context.trace_id());
+    assert_eq!(trace_context.span_id, spans[0].span_context.span_id());
+}
+
+#[test]
+fn unsigned_fields_are_integers_when_they_fit() {
+    let logs = InMemoryLogExporter::default();
+    let _subscriber = tracing_subscriber::registry()
+        .with(init_otlp_log_layer_with_exporter(logs.clone()))
+        .set_default();
+
+    tracing::info!(count = 42u64, big = u64::MAX, "counted");
+
+    let logs = logs.get_emitted_logs().unwrap();
+    let record = &logs[0].record;
+    assert_eq!(get_int(record, "count"), Some(42));
+    assert_eq!(get_str(record, "big"), Some("18446744073709551615"));
+}
+
+#[test]
+fn wide_integer_and_bytes_span_fields_keep_their_type() {
+    let logs = InMemoryLogExporter::default();
+    let _subscriber = tracing_subscriber::registry()
+        .with(
+            init_otlp_log_layer_with_exporter(logs.clone())
+                .with_span_fields(DuplicateKeys::EventWins),
+        )
+        .set_default();
+
+    tracing::info_span!(
+        "request",
+        small = 42u128,
+        negative = -42i128,
+        big = u128::MAX,
+        data = &b"\x00\xff"[..],
+    )
+    .in_scope(|| tracing::info!("in the span"));
+
+    let logs = logs.get_emitted_logs().unwrap();
+    let record = &logs[0].record;
+    assert_eq!(get_int(record, "small"), Some(42));
+    assert_eq!(get_int(record, "negative"), Some(-42));
+    assert_eq!(
+        get_str(record, "big"),
+        Some("340282366920938463463374607431768211455")
+    );
+    assert_eq!(get_bytes(record, "data"), Some(&[0x00, 0xff][..]));
+}
+
+/// The log record of an event with `user = "event"` in a span with
+/// `user = "span"`, and `region` in both an outer and an inner span
+fn with_span_fields(duplicates: DuplicateKeys) -> SdkLogRecord {
+    let logs = InMemoryLogExporter::default();
+    let _subscriber = tracing_subscriber::registry()
+        .with(init_otlp_log_layer_with_exporter(logs.clone()).with_span_fields(duplicates))
+        .set_default();
+
+    let outer = tracing::info_span!("outer", region = "outer", tenant = 7);
+    let inner = tracing::info_span!(parent: &outer, "inner", region = "inner", user = "span");
+    inner.in_scope(|| tracing::info!(user = "event", "hello"));
+
+    logs.get_emitted_logs().unwrap().remove(0).record
+}
+
+#[test]
+fn event_field_wins_over_span_field() {
+    let record = with_span_fields(DuplicateKeys::EventWins);
+    assert_eq!(get_str(&record, "user"), Some("event"));
+    assert_eq!(get_str(&record, "span.user"), None);
+    assert_eq!(get_int(&record, "tenant"), Some(7));
+}
+
+#[test]
+fn span_field_wins_over_event_field() {
+    let record = with_span_fields(DuplicateKeys::SpanWins);
+    assert_eq!(get_str(&record, "user"), Some("span"));
+    assert_eq!(
+        record
+            .attributes_iter()
+            .filter(|(key, _)| key.as_str() == "user")
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn namespaced_span_field_is_kept_next_to_event_field() {
+    let record = with_span_fields(DuplicateKeys::Namespaced);
+    assert_eq!(get_str(&record, "user"), Some("event"));
+    assert_eq!(get_str(&record, "span.user"), Some("span"));
+}
+
+#[test]
+fn inner_span_field_hides_outer_span_field() {
+    for duplicates in [
+        DuplicateKeys::EventWins,
+        DuplicateKeys::SpanWins,
+        DuplicateKeys::Namespaced,
+    ] {
+        let record = with_span_fields(duplicates);
+        let regions: Vec<_> = record
+            .attributes_iter()
+            .filter(|(key, _)| key.as_str() == "region")
+            .collect();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(get_str(&record, "region"), Some("inner"));
+    }
+}
+
+#[tokio::test]
+async fn failed_requests_in_one_outer_span_are_logged_separately() {
+    let logs = InMemoryLogExporter::default();
+    let _subscriber = tracing_subscriber::registry()
+        .with(init_otlp_log_layer_with_exporter(logs.clone()))
+        .set_default();
+    let app = Router::new()
+        .route(
+            "/logged",
+            get(|| async {
+                tracing::error!("handler failed");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }),
+        )
+        .route(
+            "/silent",
+            get(|| async { StatusCode::INTERNAL_SERVER_ERROR }),
+        )
+        .layer(opentelemetry_tracing_layer().with_error_logs());
+
+    async {
+        for uri in ["/logged", "/silent"] {
+            let request = http::Request::get(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+    }
+    .instrument(tracing::info_span!("outer"))
+    .await;
+
+    let logs = logs.get_emitted_logs().unwrap();
+    let messages: Vec<_> = logs
+        .iter()
+        .map(|log| format!("{:?}", log.record.body()))
+        .collect();
+    assert_eq!(logs.len(), 2, "{messages:?}");
+    assert!(messages[0].contains("handler failed"));
+    assert!(messages[1].contains("HTTP request failed"));
+    assert_eq!(get_str(&logs[1].record, "http.route"), Some("/silent"));
+}
@@ -0,0 +1,98 @@
+//! The path parameters recorded by `with_path_params`
+
+use axum::{Router, body::Body, routing::get};
+use axum_otlp_honeycomb::{opentelemetry_tracing_layer, testing::*};
+use opentelemetry_sdk::trace::SpanData;
+use tower::ServiceExt;
+use tracing_subscriber::prelude::*;
+
+/// The span of a request for `uri` to the routes `/users/{id}/tokens/{token}`,
+/// `/orders/{email}`, `/files/{*path}` and `/health`, with the layer added by
+/// `with_layer`
+async fn request_span(with_layer: impl FnOnce(Router) -> Router, uri: &str) -> SpanData {
+    let exporter = InMemorySpanExporter::default();
+    let _subscriber = tracing_subscriber::registry()
+        .with(init_otlp_layer_with_exporter(exporter.clone()))
+        .set_default();
+    let app = Router::new()
+        .route("/users/{id}/tokens/{token}", get(|| async {}))
+        .route("/orders/{email}", get(|| async {}))
+        .route("/files/{*path}", get(|| async {}))
+        .route("/health", get(|| async {}));
+    let request = http::Request::get(uri).body(Body::empty()).unwrap();
+    with_layer(app).oneshot(request).await.unwrap();
+    exporter.get_finished_spans().unwrap().remove(0)
+}
+
+fn with_prefix(app: Router) -> Router {
+    app.layer(opentelemetry_tracing_layer().with_path_params("route.param"))
+}
+
+#[tokio::test]
+async fn params_are_recorded_with_the_configured_prefix() {
+    let span = request_span(with_prefix, "/users/42/tokens/secret").await;
+    assert_eq!(get_span_str(&span, "route.param.id"), Some("42"));
+    assert_eq!(get_span_str(&span, "http.route.param.id"), None);
+
+    let span = request_span(with_prefix, "/files/a/b/c.txt").await;
+    assert_eq!(get_span_str(&span, "route.param.path"), Some("a/b/c.txt"));
+
+    let span = request_span(with_prefix, "/health").await;
+    assert!(
+        span.attributes
+            .iter()
+            .all(|kv| !kv.key.as_str().starts_with("route.param.")),
+        "{:?}",
+        span.attributes
+    );
+}
+
+fn with_redaction(app: Router) -> Router {
+    app.layer(
+        opentelemetry_tracing_layer()
+            .with_path_params("http.route.param")
+            .with_redacted_path_params(["email"]),
+    )
+}
+
+#[tokio::test]
+async fn redacted_params_are_not_recorded() {
+    let span = request_span(with_redaction, "/users/42/tokens/secret").await;
+    assert_eq!(get_span_str(&span, "http.route.param.id"), Some("42"));
+    assert_eq!(
+        get_span_str(&span, "http.route.param.token"),
+        Some("[REDACTED]")
+    );
+
+    let span = request_span(with_redaction, "/orders/someone@example.com").await;
+    assert_eq!(
+        get_span_str(&span, "http.route.param.email"),
+        Some("[REDACTED]")
+    );
+}
+
+fn with_max_length(app: Router) -> Router {
+    app.layer(
+        opentelemetry_tracing_layer()
+            .with_path_params("http.route.param")
+            .with_max_attribute_length(8)
+            .with_truncation_markers(),
+    )
+}
+
+#[tokio::test]
+async fn long_params_are_truncated() {
+    let span = request_span(with_max_length, "/files/abcdefghijkl").await;
+    assert_eq!(get_span_str(&span, "http.route.param.path"), Some("abcde…"));
+    assert_eq!(
+        get_span_bool(&span, "http.route.param.path.truncated"),
+        Some(true)
+    );
+
+    let span = request_span(with_max_length, "/orders/abc").await;
+    assert_eq!(get_span_str(&span, "http.route.param.email"), Some("abc"));
+    assert_eq!(
+        get_span_bool(&span, "http.route.param.email.truncated"),
+        None
+    );
+}
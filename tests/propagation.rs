@@ -0,0 +1,63 @@
+//! The trace context returned in the response headers
+
+use axum::{Router, body::Body, routing::get};
+use axum_otlp_honeycomb::{opentelemetry_tracing_layer, testing::*};
+use opentelemetry_sdk::trace::Sampler;
+use tower::ServiceExt;
+use tracing_subscriber::prelude::*;
+
+/// The response headers of a request with `headers`, `sampler` deciding at the edge
+async fn response_headers(sampler: Sampler, headers: &[(&str, &str)]) -> http::HeaderMap {
+    let _subscriber = tracing_subscriber::registry()
+        .with(init_otlp_layer_with_sampler(
+            InMemorySpanExporter::default(),
+            sampler,
+        ))
+        .set_default();
+    let app = Router::new()
+        .route("/", get(|| async {}))
+        .layer(opentelemetry_tracing_layer());
+    let mut request = http::Request::get("/");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = app
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    response.headers().clone()
+}
+
+#[tokio::test]
+async fn unsampled_edge_decision_returns_unsampled_traceparent() {
+    let headers = response_headers(Sampler::AlwaysOff, &[]).await;
+    let traceparent = headers["traceparent"].to_str().unwrap();
+    assert!(traceparent.starts_with("00-"));
+    assert!(traceparent.ends_with("-00"));
+}
+
+#[tokio::test]
+async fn sampled_edge_decision_returns_sampled_traceparent() {
+    let headers = response_headers(Sampler::AlwaysOn, &[]).await;
+    let traceparent = headers["traceparent"].to_str().unwrap();
+    assert!(traceparent.starts_with("00-"));
+    assert!(traceparent.ends_with("-01"));
+}
+
+#[tokio::test]
+async fn tracestate_survives_a_round_trip() {
+    let headers = response_headers(
+        Sampler::ParentBased(Box::new(Sampler::AlwaysOn)),
+        &[
+            (
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ),
+            ("tracestate", "vendor=value"),
+        ],
+    )
+    .await;
+    assert_eq!(headers["tracestate"], "vendor=value");
+    let traceparent = headers["traceparent"].to_str().unwrap();
+    assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+}
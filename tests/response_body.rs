@@ -0,0 +1,68 @@
+//! Status of the request span when the response body is not sent completely
+
+use axum::{Router, body::Body, routing::get};
+use axum_otlp_honeycomb::{opentelemetry_tracing_layer, testing::*};
+use futures_util::StreamExt;
+use opentelemetry::trace::Status;
+use opentelemetry_sdk::trace::SpanData;
+use tower::ServiceExt;
+use tracing_subscriber::prelude::*;
+
+/// A body of two chunks
+async fn streamed() -> Body {
+    Body::from_stream(futures_util::stream::iter([
+        Ok::<_, std::io::Error>("first"),
+        Ok("second"),
+    ]))
+}
+
+/// The span of a `method` request whose response body is read to the end, or
+/// only the first chunk, or not at all for `HEAD`
+async fn read_body(method: http::Method, to_end: bool) -> SpanData {
+    let exporter = InMemorySpanExporter::default();
+    let _subscriber = tracing_subscriber::registry()
+        .with(init_otlp_layer_with_exporter(exporter.clone()))
+        .set_default();
+    let app = Router::new()
+        .route("/", get(streamed))
+        .layer(opentelemetry_tracing_layer().with_ok_status());
+    let request = http::Request::builder()
+        .method(method.clone())
+        .uri("/")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    let mut body = response.into_body().into_data_stream();
+    if method != http::Method::HEAD {
+        body.next().await.unwrap().unwrap();
+    }
+    if to_end {
+        while body.next().await.is_some() {}
+    }
+    drop(body);
+    exporter.get_finished_spans().unwrap().remove(0)
+}
+
+#[tokio::test]
+async fn body_dropped_halfway_is_an_error() {
+    let span = read_body(http::Method::GET, false).await;
+    assert!(matches!(span.status, Status::Error { .. }));
+    assert_eq!(get_span_str(&span, "error.source"), Some("body"));
+    assert_eq!(
+        get_span_str(&span, "exception.message"),
+        Some("response body dropped before completion")
+    );
+}
+
+#[tokio::test]
+async fn body_sent_completely_is_ok() {
+    let span = read_body(http::Method::GET, true).await;
+    assert_eq!(span.status, Status::Ok);
+    assert_eq!(get_span_str(&span, "error.source"), None);
+}
+
+#[tokio::test]
+async fn body_of_head_response_is_not_sent() {
+    let span = read_body(http::Method::HEAD, false).await;
+    assert_eq!(span.status, Status::Ok);
+}